@@ -1,20 +1,59 @@
 use pinocchio::{
     ProgramResult,
-    account_info::AccountInfo,
-    memory::{sol_memcpy, sol_memset},
+    account_info::{AccountInfo, MAX_PERMITTED_DATA_INCREASE, Ref, RefMut},
     program_error::ProgramError,
     pubkey::Pubkey,
     sysvars::rent::Rent,
 };
 use pinocchio_log::log;
-use pinocchio_system::instructions::Transfer;
+use pinocchio_system::instructions::{CreateAccount, Transfer};
+
+#[cfg(not(target_os = "solana"))]
+use host_memory::{sol_memcpy, sol_memmove, sol_memset};
+#[cfg(target_os = "solana")]
+use pinocchio::memory::{sol_memcpy, sol_memmove, sol_memset};
 
 pub const EXT_META_LEN: usize = 4;
 
+// the stored state byte keeps the ExtensionState in its low bits and the number
+// of alignment bytes inserted between the header and the payload in bits 4..=6.
+// bit 7 marks a TLV whose header is followed by the 32 byte pubkey of the
+// authority allowed to mutate it, ahead of the padding
+pub const EXT_STATE_MASK: u8 = 0x0f;
+pub const EXT_PADDING_MASK: u8 = 0x70;
+pub const EXT_PADDING_SHIFT: u8 = 4;
+pub const EXT_AUTHORITY_FLAG: u8 = 0x80;
+pub const EXT_AUTHORITY_LEN: usize = 32;
+// state nibble of a vacated TLV kept for reuse, see REUSE_FREE_SLOTS
+pub const EXT_STATE_FREE: u8 = 0x02;
+// state nibble of the header in front of a raw region, see append_raw_region.
+// the walk ends at it
+pub const EXT_STATE_RAW: u8 = 0x0f;
+pub const MAX_EXT_ALIGN: usize = 8;
+
+// solana caps account data at 10MB
+pub const MAX_PERMITTED_DATA_LENGTH: usize = 10 * 1024 * 1024;
+
 #[repr(u8)]
 pub enum StateExtensionError {
     ExtensionDataAleadyZerod,
     ExtensionDataIsNotInitialized,
+    AccountBeingClosed,
+    ExtensionTooLarge,
+    ExtensionLengthMismatch,
+    ExtensionDataAlreadyInitialized,
+    OverlappingExtensions,
+    ExtensionNotFound,
+    BaseStateNotInitialized,
+    DuplicateExtension,
+    AccountSizeLimitExceeded,
+    MarkerAlreadyPresent,
+    InvalidStateTransition,
+    MisalignedExtensionData,
+    AccountAlreadyBorrowed,
+    TooManyExtensions,
+    InvalidExtensionType,
+    ExtensionNotRemovable,
 }
 
 impl From<StateExtensionError> for ProgramError {
@@ -28,6 +67,12 @@ pub trait ExtensionEnum: Sized + Clone + PartialEq + Eq {
     fn as_u8(&self) -> u8;
 }
 
+// decodes the state byte of the TLV at `position`, None if out of bounds or
+// not a known state
+pub fn read_state(data: &[u8], position: usize) -> Option<ExtensionState> {
+    ExtensionState::from_u8(*data.get(position + 1)? & EXT_STATE_MASK)
+}
+
 #[repr(u8)]
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum ExtensionState {
@@ -53,7 +98,12 @@ impl ExtensionEnum for ExtensionState {
 }
 
 pub trait Extension: Sized {
+    // may be 0 for a flag whose presence is the signal, its TLV is then the
+    // header alone and unpack hands back a reference to the zero sized type.
+    // a flag cannot use type 0, see check_payload_len
     const LEN: u16;
+    // payload start alignment, a power of two up to MAX_EXT_ALIGN
+    const ALIGN: usize = 1;
 
     type ExtensionEnum: ExtensionEnum;
     // enum used to identity Extension
@@ -66,318 +116,7187 @@ pub trait Extension: Sized {
         Self::LEN as usize + EXT_META_LEN
     }
 
+    // checked before the extension is written to the account
+    fn validate(&self) -> Result<(), ProgramError> {
+        Ok(())
+    }
+
+    // checked by set_extension_state before the state byte is rewritten
+    fn is_valid_transition(_from: &ExtensionState, _to: &ExtensionState) -> bool {
+        true
+    }
+
+    /// # Safety
+    ///
+    /// Self must be a `repr(C)` type without padding bytes whose size is LEN,
+    /// the returned slice covers exactly LEN bytes of self.
     unsafe fn pack(&self) -> &[u8] {
         unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, Self::LEN as usize) }
     }
 
+    /// # Safety
+    ///
+    /// Self must be a `repr(C)` type of LEN bytes that is valid for any bit
+    /// pattern, the payload bytes are reinterpreted as it without a copy.
     unsafe fn unpack(bytes: &[u8]) -> Result<&Self, ProgramError> {
         if bytes.len() != Self::LEN as usize {
             return Err(ProgramError::InvalidAccountData);
         }
 
+        if !(bytes.as_ptr() as usize).is_multiple_of(core::mem::align_of::<Self>()) {
+            return Err(StateExtensionError::MisalignedExtensionData.into());
+        }
+
         unsafe { Ok(&*(bytes.as_ptr() as *const Self)) }
     }
 }
 
+/// types valid for any bit pattern, safe to read straight out of account data
+///
+/// # Safety
+///
+/// Every bit pattern of size_of::<Self>() bytes must be a valid value of the
+/// type, read_field copies such bytes out of a payload without checking them.
+pub unsafe trait Pod: Copy + 'static {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for u128 {}
+unsafe impl Pod for i8 {}
+unsafe impl Pod for i16 {}
+unsafe impl Pod for i32 {}
+unsafe impl Pod for i64 {}
+unsafe impl Pod for i128 {}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
 #[derive(Debug)]
 pub struct ExtensionInfo<'e, E: Extension> {
     pub ext: &'e E,
     pub position: usize,
     pub state: ExtensionState,
+    pub len: u16,
 }
 
-pub trait StateExtension {
-    const BASE_STATE_LEN: usize;
-    const OWNER_PROGRAM: Pubkey;
-    const MAX_EXTENSIONS: u8;
-    const EXT_START_MARKER: [u8; 8];
+impl<'e, E: Extension> ExtensionInfo<'e, E> {
+    // views the start of the payload as a T, e.g. an older version of the struct
+    // sharing a common prefix
+    pub fn as_prefix<T: Pod>(&self) -> Option<&'e T> {
+        let ptr = self.ext as *const E as *const u8;
 
-    fn len() -> usize {
-        Self::BASE_STATE_LEN
+        if core::mem::size_of::<T>() > self.len as usize
+            || !(ptr as usize).is_multiple_of(core::mem::align_of::<T>())
+        {
+            return None;
+        }
+
+        Some(unsafe { &*(ptr as *const T) })
     }
 
-    fn check_ext_marker(bytes: &[u8]) -> bool {
-        bytes == Self::EXT_START_MARKER.as_slice()
+    // by value copy that does not alias the account data
+    pub fn to_owned(&self) -> E
+    where
+        E: Copy,
+    {
+        *self.ext
     }
+}
 
-    unsafe fn add_extension<E: Extension>(
-        acc: &AccountInfo,
-        fee_payer: &AccountInfo,
-        rent: &AccountInfo,
-        extension: &E,
-    ) -> ProgramResult {
-        log!("Add Extension : {}", E::ext_type());
+// payload of a TLV reserved by extension_writer, filled in place. The TLV stays
+// Zerod until finish validates the payload and marks it Initialized
+pub struct ExtensionWriter<'a, E: Extension> {
+    data: RefMut<'a, [u8]>,
+    position: usize,
+    payload_start: usize,
+    _ext: core::marker::PhantomData<E>,
+}
+
+impl<E: Extension> ExtensionWriter<'_, E> {
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn payload(&mut self) -> &mut [u8] {
+        &mut self.data[self.payload_start..self.payload_start + E::LEN as usize]
+    }
+
+    pub fn finish(mut self) -> ProgramResult {
+        let payload = &self.data[self.payload_start..self.payload_start + E::LEN as usize];
+
+        unsafe { E::unpack(payload)? }.validate()?;
+
+        set_state_byte(&mut self.data, self.position, ExtensionState::Initialized);
 
-        if unsafe { acc.owner() } != &Self::OWNER_PROGRAM {
+        Ok(())
+    }
+}
+
+// read only view over an account checked once at construction, every read walks
+// the same borrowed slice instead of borrowing the account again
+pub struct StateView<'a, S: StateExtension> {
+    data: Ref<'a, [u8]>,
+    _state: core::marker::PhantomData<S>,
+}
+
+impl<'a, S: StateExtension> StateView<'a, S> {
+    pub fn new(acc: &'a AccountInfo) -> Result<Self, ProgramError> {
+        if !S::is_valid_owner(unsafe { acc.owner() }) {
             return Err(ProgramError::IllegalOwner);
         }
 
-        if acc.data_is_empty() {
-            return Err(ProgramError::InvalidAccountData);
+        let data = acc.try_borrow_data()?;
+
+        if data.len() < S::len() {
+            return Err(S::to_program_error(
+                StateExtensionError::BaseStateNotInitialized,
+            ));
         }
 
-        let data_len = {
-            let data = acc.try_borrow_data()?;
+        Ok(Self {
+            data,
+            _state: core::marker::PhantomData,
+        })
+    }
 
-            if data.len() < Self::len() {
-                return Err(ProgramError::InvalidAccountData);
-            }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
 
-            data.len()
-        };
+    pub fn get<E: Extension>(&self, ext_type: E::ExtensionEnum) -> Option<ExtensionInfo<'_, E>> {
+        S::get_extension_from_acc_data_unchecked(&self.data, ext_type)
+    }
 
-        let rent = Rent::from_account_info(rent)?;
+    pub fn has<V: ExtensionEnum>(&self, ext_type: V) -> bool {
+        S::find_tlv(&self.data, ext_type.as_u8()).is_some()
+    }
+
+    pub fn count(&self) -> usize {
+        S::extension_stats(&self.data).map_or(0, |(count, _)| count)
+    }
+
+    pub fn variants<V: ExtensionEnum>(&self) -> Vec<V> {
+        S::get_extension_variants_from_acc_data_uncheked(&self.data).unwrap_or_default()
+    }
+}
 
-        let no_extensions = data_len == Self::len();
+// TLV layouts this crate has written, as told apart by detect_format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatVersion {
+    // base state only, or followed by nothing but zeros, nothing to classify
+    BaseOnly,
+    // 4 byte meta with no alignment padding
+    Original,
+    // at least one TLV carries alignment padding in its state byte
+    Aligned,
+    // at least one TLV stores a mutation authority after its header
+    WithAuthority,
+    // marker missing, truncated TLVs or state bits no version defines
+    Unknown,
+}
+
+// change in the extensions of an account between two snapshots
+#[derive(Debug, PartialEq, Eq)]
+pub struct ExtensionDiff<V: ExtensionEnum> {
+    pub added: Vec<V>,
+    pub removed: Vec<V>,
+    // (type, state before, state after)
+    pub state_changed: Vec<(V, ExtensionState, ExtensionState)>,
+}
+
+// which side wins when both merged regions hold the same extension type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    KeepA,
+    KeepB,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TlvEntry<'a> {
+    pub position: usize,
+    pub ext_type: u8,
+    pub state: u8,
+    pub padding: u8,
+    pub len: u16,
+    pub authority: Option<&'a Pubkey>,
+    pub payload: &'a [u8],
+}
 
-        // if appending for fist time
-        let new_space_to_allocate = if no_extensions {
-            Self::EXT_START_MARKER.len() + E::ext_with_meta_len()
+impl TlvEntry<'_> {
+    pub fn payload_start(&self) -> usize {
+        let authority_len = if self.authority.is_some() {
+            EXT_AUTHORITY_LEN
         } else {
-            E::ext_with_meta_len()
+            0
         };
 
-        // transfer lamports for min rent exempt
-        Transfer {
-            from: fee_payer,
-            to: acc,
-            lamports: rent.minimum_balance(new_space_to_allocate),
+        self.position + EXT_META_LEN + authority_len + self.padding as usize
+    }
+
+    pub fn end(&self) -> usize {
+        self.payload_start() + self.len as usize
+    }
+}
+
+// byte level walk over the TLVs, stops on the first truncated entry and in
+// front of an all zero tail.
+// entries are yielded in on-disk order, ascending position. that is the order
+// they were added in unless REUSE_FREE_SLOTS put a later one into an earlier
+// slot or sort_extensions_by / touch_extension moved them; every enumeration
+// method in this crate is built on this walk and keeps that order
+pub struct TlvIter<'a> {
+    data: &'a [u8],
+    start: usize,
+    cursor: usize,
+    malformed: bool,
+    include_free: bool,
+    raw_region: Option<usize>,
+}
+
+impl<'a> TlvIter<'a> {
+    pub fn new(data: &'a [u8], start: usize) -> Self {
+        Self {
+            data,
+            start,
+            cursor: start,
+            malformed: false,
+            include_free: false,
+            raw_region: None,
         }
-        .invoke()?;
+    }
 
-        // realloc acc data and fill it with 0's
-        acc.realloc(acc.data_len() + new_space_to_allocate, false)?;
+    // also yield the vacated slots the walk otherwise steps over
+    pub fn with_free_slots(mut self) -> Self {
+        self.include_free = true;
+        self
+    }
 
-        let mut data = acc.try_borrow_mut_data()?;
+    // end of the last well formed TLV
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
 
-        let mut buffer = Vec::new();
+    pub fn is_malformed(&self) -> bool {
+        self.malformed
+    }
 
-        if no_extensions {
-            buffer.extend_from_slice(Self::EXT_START_MARKER.as_slice());
-        }
+    // header position of the raw region the walk stopped at, if it reached one
+    pub fn raw_region_start(&self) -> Option<usize> {
+        self.raw_region
+    }
+}
 
-        unsafe {
-            buffer.push(E::ext_type());
-            buffer.push(ExtensionState::Initialized.as_u8());
-            buffer.extend_from_slice(E::ext_len().to_le_bytes().as_slice());
+impl<'a> Iterator for TlvIter<'a> {
+    type Item = TlvEntry<'a>;
 
-            buffer.extend_from_slice(extension.pack());
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.malformed || self.cursor >= self.data.len() {
+                return None;
+            }
 
-            if let Some(data) = data.get_mut(data_len..) {
-                sol_memcpy(data, &buffer, buffer.len());
-            } else {
-                return Err(ProgramError::InvalidAccountData);
+            let position = self.cursor;
+
+            // the rest of the data being zero, e.g. the unused tail of a
+            // preallocated account, ends the walk like the end of the data
+            if self.data[position..].iter().all(|byte| *byte == 0) {
+                return None;
             }
-        };
 
-        Ok(())
-    }
+            let Some(header) = self.data.get(position..position + EXT_META_LEN) else {
+                self.malformed = true;
+                return None;
+            };
 
-    unsafe fn zero_out_extension_data<E: Extension>(
-        acc: &AccountInfo,
-        ext_type: E::ExtensionEnum,
-    ) -> ProgramResult {
-        log!("ZeroOut Extension : {}", E::ext_type());
-        if let Some(ExtensionInfo {
-            ext: _,
-            position,
-            state,
-        }) = unsafe { Self::get_extension::<E>(acc, ext_type) }
-        {
-            let ext_data_start = position + EXT_META_LEN;
-            if state == ExtensionState::Zerod {
-                unsafe {
-                    let mut data = acc.try_borrow_mut_data()?;
+            if header[1] & EXT_STATE_MASK == EXT_STATE_RAW {
+                self.raw_region = Some(position);
+                return None;
+            }
 
-                    if let Some(data) = data.get_mut(ext_data_start..) {
-                        sol_memset(data, 0, E::ext_len() as usize);
-                    } else {
-                        return Err(ProgramError::InvalidAccountData);
-                    }
-                }
-            } else {
-                return Err(StateExtensionError::ExtensionDataAleadyZerod.into());
+            let padding = (header[1] & EXT_PADDING_MASK) >> EXT_PADDING_SHIFT;
+            let authority_len = authority_len(header[1]);
+            let payload_start = position + EXT_META_LEN + authority_len + padding as usize;
+            let len = u16::from_le_bytes([header[2], header[3]]);
+
+            // a payload may never reach back before the walk start (marker or base
+            // state) nor past the end of the data
+            let Some(payload_end) = payload_start.checked_add(len as usize) else {
+                self.malformed = true;
+                return None;
+            };
+
+            if payload_start < self.start || payload_end > self.data.len() {
+                self.malformed = true;
+                return None;
             }
-        }
-        Ok(())
-    }
 
-    unsafe fn update_extension<E: Extension>(
-        acc: &AccountInfo,
-        ext_type: E::ExtensionEnum,
-        extension: &E,
-    ) -> ProgramResult {
-        log!("Mutate Extension : {}", E::ext_type());
+            let payload = &self.data[payload_start..payload_end];
 
-        if let Some(ExtensionInfo {
-            ext: _,
-            position,
-            state,
-        }) = unsafe { Self::get_extension::<E>(acc, ext_type) }
-        {
-            if state != ExtensionState::Zerod {
-                unsafe {
-                    let mut data = acc.try_borrow_mut_data()?;
+            let authority = (authority_len != 0).then(|| {
+                let start = position + EXT_META_LEN;
+                <&Pubkey>::try_from(&self.data[start..start + EXT_AUTHORITY_LEN]).unwrap()
+            });
 
-                    let mut buffer = Vec::new();
-                    buffer.push(E::ext_type());
-                    buffer.push(ExtensionState::Initialized as u8);
-                    buffer.extend_from_slice(E::ext_len().to_le_bytes().as_slice());
-                    buffer.extend_from_slice(extension.pack());
+            self.cursor = payload_end;
 
-                    if let Some(data) = data.get_mut(position..) {
-                        sol_memcpy(data, &buffer, buffer.len());
-                    }
-                }
-            } else {
-                return Err(StateExtensionError::ExtensionDataIsNotInitialized.into());
+            if !self.include_free && header[1] & EXT_STATE_MASK == EXT_STATE_FREE {
+                continue;
             }
+
+            return Some(TlvEntry {
+                position,
+                ext_type: header[0],
+                state: header[1] & EXT_STATE_MASK,
+                padding,
+                len,
+                authority,
+                payload,
+            });
         }
+    }
+}
 
-        Ok(())
+// (type, state, payload) of every TLV in raw account bytes, for tooling that has
+// the base length and marker but not the program's types. base_len may include
+// any header a snapshot format puts in front of the account data
+pub fn parse_snapshot_account(
+    bytes: &[u8],
+    base_len: usize,
+    marker: &[u8],
+) -> Result<Vec<(u8, ExtensionState, Vec<u8>)>, ProgramError> {
+    if bytes.len() < base_len {
+        return Err(ProgramError::InvalidAccountData);
     }
 
-    fn get_extension_variants<V: ExtensionEnum>(acc: &AccountInfo) -> Option<Vec<V>> {
-        if unsafe { acc.owner() } != &Self::OWNER_PROGRAM {
-            return None;
-        }
+    if bytes.len() == base_len {
+        return Ok(Vec::new());
+    }
 
-        let data_len = acc.data_len();
+    let ext_data_start = base_len + marker.len();
 
-        if data_len <= Self::len() {
-            return None;
-        }
+    if bytes.get(base_len..ext_data_start) != Some(marker) {
+        return Err(ProgramError::InvalidAccountData);
+    }
 
-        let data =
-            unsafe { core::slice::from_raw_parts(acc.try_borrow_data().ok()?.as_ptr(), data_len) };
+    let mut tlvs = TlvIter::new(bytes, ext_data_start);
 
-        Self::get_extension_variants_from_acc_data_uncheked(data)
+    let extensions = tlvs
+        .by_ref()
+        .map(|tlv| {
+            ExtensionState::from_u8(tlv.state)
+                .map(|state| (tlv.ext_type, state, tlv.payload.to_vec()))
+                .ok_or(ProgramError::InvalidAccountData)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if tlvs.is_malformed() {
+        return Err(ProgramError::InvalidAccountData);
     }
 
-    fn get_extension_variants_from_acc_data_uncheked<V: ExtensionEnum>(
-        data: &[u8],
-    ) -> Option<Vec<V>> {
-        let data_len = data.len();
+    Ok(extensions)
+}
 
-        let ext_marker_start = Self::len();
+// plain text table of the account layout for CLI tooling: base length,
+// marker, then index/type/state/offset/len of every TLV (free slots included),
+// ending with where and why the walk stopped if it did not reach the end
+#[cfg(feature = "std")]
+pub fn describe_layout(data: &[u8], base_len: usize, marker: &[u8]) -> String {
+    use std::fmt::Write;
 
-        if !Self::check_ext_marker(
-            data.get(ext_marker_start..(ext_marker_start + Self::EXT_START_MARKER.len()))?,
-        ) {
-            return None;
-        }
+    let mut out = String::new();
 
-        let mut ext_data_cursor = Self::len() + Self::EXT_START_MARKER.len();
+    if data.len() < base_len {
+        let _ = writeln!(out, "base: {} bytes, data is only {}", base_len, data.len());
+        return out;
+    }
 
-        let mut extensions = Vec::new();
+    let _ = writeln!(out, "base: {} bytes", base_len);
 
-        while ext_data_cursor < data_len {
-            let ext_type = match data.get(ext_data_cursor) {
-                Some(ext_type) => *ext_type,
-                None => break,
-            };
+    if data.len() == base_len {
+        let _ = writeln!(out, "marker: none, no extensions");
+        return out;
+    }
 
-            if let Some(ext) = V::from_u8(ext_type) {
-                extensions.push(ext);
-            }
+    let ext_data_start = base_len + marker.len();
 
-            ext_data_cursor += 1;
+    if data.get(base_len..ext_data_start) != Some(marker) {
+        let _ = writeln!(out, "marker: missing at offset {}", base_len);
+        return out;
+    }
 
-            let _ext_state = data[ext_data_cursor];
+    let _ = writeln!(out, "marker: {} bytes at offset {}", marker.len(), base_len);
+    let _ = writeln!(out, "index  type  state        offset  len");
 
-            ext_data_cursor += 1;
+    let mut tlvs = TlvIter::new(data, ext_data_start).with_free_slots();
 
-            let ext_len: Option<u16> = data
-                .get(ext_data_cursor..(ext_data_cursor + 2))
-                .map(|d| d.try_into().ok().map(|d| u16::from_le_bytes(d)))
-                .flatten();
+    for (index, tlv) in tlvs.by_ref().enumerate() {
+        let state = match ExtensionState::from_u8(tlv.state) {
+            Some(ExtensionState::Initialized) => "Initialized".to_string(),
+            Some(ExtensionState::Zerod) => "Zerod".to_string(),
+            None if tlv.state == EXT_STATE_FREE => "Free".to_string(),
+            None => format!("0x{:02x}", tlv.state),
+        };
 
-            match ext_len {
-                Some(ext_len) => {
-                    ext_data_cursor += 2;
-                    ext_data_cursor += ext_len as usize;
-                }
-                None => break,
-            }
-        }
+        let _ = writeln!(
+            out,
+            "{:<5}  {:<4}  {:<11}  {:<6}  {}",
+            index, tlv.ext_type, state, tlv.position, tlv.len
+        );
+    }
 
-        Some(extensions)
+    if tlvs.is_malformed() {
+        let _ = writeln!(out, "stopped: malformed TLV at offset {}", tlvs.cursor());
+    } else if let Some(raw_region) = tlvs.raw_region_start() {
+        let _ = writeln!(out, "stopped: raw region at offset {}", raw_region);
     }
 
-    unsafe fn get_extension<'e, E: Extension>(
-        acc: &AccountInfo,
-        ext_type: E::ExtensionEnum,
-    ) -> Option<ExtensionInfo<'e, E>> {
-        if unsafe { acc.owner() } != &Self::OWNER_PROGRAM {
-            return None;
+    out
+}
+
+// writes E as an unpadded TLV at the start of out, returns the bytes written.
+// the payload lands EXT_META_LEN bytes into out, so an E with ALIGN above 1 is
+// only aligned if the caller places out accordingly
+pub fn pack_tlv<E: Extension>(
+    ext: &E,
+    state: ExtensionState,
+    out: &mut [u8],
+) -> Result<usize, ProgramError> {
+    ext.validate()?;
+
+    let len = E::ext_with_meta_len();
+
+    let Some(tlv) = out.get_mut(..len) else {
+        return Err(ProgramError::InvalidArgument);
+    };
+
+    tlv[0] = E::ext_type();
+    tlv[1] = state.as_u8();
+    tlv[2..EXT_META_LEN].copy_from_slice(&E::LEN.to_le_bytes());
+    tlv[EXT_META_LEN..].copy_from_slice(unsafe { ext.pack() });
+
+    Ok(len)
+}
+
+// generates a sum type over a closed set of extensions and its read_all, which
+// walks the TLVs of $state and unpacks each one as the struct its type byte maps
+// to. types outside the set, and TLVs that do not unpack, are skipped. the
+// structs are copied out, so they must be Copy
+//
+// define_extension_registry! {
+//     pub enum AnyExtension for MyState {
+//         Config(ConfigExt),
+//         Fees(FeesExt),
+//     }
+// }
+#[macro_export]
+macro_rules! define_extension_registry {
+    (
+        $vis:vis enum $name:ident for $state:ty {
+            $($variant:ident($ext:ty)),+ $(,)?
+        }
+    ) => {
+        #[derive(Clone, Copy)]
+        $vis enum $name {
+            $($variant($ext)),+
         }
 
-        let data_len = acc.data_len();
+        impl $name {
+            pub fn read_all(data: &[u8]) -> Vec<Self> {
+                <$state as $crate::StateExtension>::tlv_iter(data)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|tlv| {
+                        $(
+                            if tlv.ext_type == <$ext as $crate::Extension>::ext_type() {
+                                return unsafe { <$ext as $crate::Extension>::unpack(tlv.payload) }
+                                    .ok()
+                                    .map(|ext| Self::$variant(*ext));
+                            }
+                        )+
 
-        if data_len < Self::len() + Self::EXT_START_MARKER.len() {
-            return None;
+                        None
+                    })
+                    .collect()
+            }
         }
+    };
+}
 
-        let data =
-            unsafe { core::slice::from_raw_parts(acc.try_borrow_data().ok()?.as_ptr(), data_len) };
+pub trait StateExtension {
+    const BASE_STATE_LEN: usize;
+    const OWNER_PROGRAM: Pubkey;
+    const MAX_EXTENSIONS: u8;
+    // any length, including empty
+    const EXT_START_MARKER: &[u8];
+    // allow add_extension to fund an account that has been drained of lamports
+    const ALLOW_REVIVE: bool = false;
+    // try_get_extension_from_acc_data fails on a stored type byte the caller's
+    // enum does not decode instead of skipping it
+    const STRICT_EXTENSION_TYPES: bool = false;
+    // added to every StateExtensionError code by to_program_error so they do not
+    // collide with the program's own custom errors
+    const ERROR_CODE_OFFSET: u32 = 0;
+    // removing a TLV leaves its bytes in place as a free slot instead of shifting
+    // the rest down and shrinking the account, and adding fills the first free
+    // slot the TLV fits before growing. trades unreclaimed rent for fewer reallocs
+    const REUSE_FREE_SLOTS: bool = false;
 
-        Self::get_extension_from_acc_data_unchecked(data, ext_type)
+    fn len() -> usize {
+        Self::BASE_STATE_LEN
     }
 
-    fn get_extension_from_acc_data_unchecked<'e, E: Extension>(
-        data: &'e [u8],
-        ext_type: E::ExtensionEnum,
-    ) -> Option<ExtensionInfo<'e, E>> {
-        let data_len = data.len();
+    // override to accept accounts owned by more than one program. every read and
+    // write goes through here, so an unset OWNER_PROGRAM is caught in debug builds
+    fn is_valid_owner(owner: &Pubkey) -> bool {
+        debug_assert!(
+            Self::OWNER_PROGRAM != Pubkey::default(),
+            "OWNER_PROGRAM is the default pubkey"
+        );
 
-        let ext_marker_start = Self::len();
+        owner == &Self::OWNER_PROGRAM
+    }
 
-        if !Self::check_ext_marker(
-            data.get(ext_marker_start..ext_marker_start + Self::EXT_START_MARKER.len())?,
-        ) {
-            return None;
+    // every StateExtensionError raised for Self comes out through here, only
+    // Extension::unpack and the free helpers without a StateExtension use the
+    // plain From conversion. an offset near u32::MAX wraps instead of panicking
+    fn to_program_error(e: StateExtensionError) -> ProgramError {
+        ProgramError::Custom(Self::ERROR_CODE_OFFSET.wrapping_add(e as u32))
+    }
+
+    fn check_ext_marker(bytes: &[u8]) -> bool {
+        bytes == Self::EXT_START_MARKER
+    }
+
+    // types that must be added and removed together with ext_type, the group
+    // includes ext_type itself
+    fn extension_group(_ext_type: u8) -> Option<&'static [u8]> {
+        None
+    }
+
+    // false pins ext_type: it can still be updated, but removing or zeroing it is
+    // an ExtensionNotRemovable
+    fn is_removable(_ext_type: u8) -> bool {
+        true
+    }
+
+    // upper bound on the stored length of ext_type checked by validate_lengths,
+    // None leaves the type unbounded
+    fn max_len_for_type(_ext_type: u8) -> Option<u16> {
+        None
+    }
+
+    /// # Safety
+    ///
+    /// acc's data grows and a TLV is written at its end, so no reference from
+    /// get_extension or the other detached readers of acc may be used after the
+    /// call. acc's owner must not be reassigned while it runs.
+    unsafe fn add_extension<E: Extension>(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        extension: &E,
+    ) -> ProgramResult {
+        extension.validate()?;
+
+        unsafe {
+            append_tlv::<Self>(
+                acc,
+                fee_payer,
+                rent,
+                E::ext_type(),
+                E::ALIGN,
+                ExtensionState::Initialized,
+                None,
+                extension.pack(),
+            )
+        }
+    }
+
+    /// like add_extension, storing the authority that the _authorized methods
+    /// require to mutate the TLV; the plain mutators refuse it from then on
+    ///
+    /// # Safety
+    ///
+    /// The same as add_extension: the account is reallocated, references from
+    /// the detached readers must not be held across the call.
+    unsafe fn add_extension_with_authority<E: Extension>(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        extension: &E,
+        authority: &Pubkey,
+    ) -> ProgramResult {
+        extension.validate()?;
+
+        unsafe {
+            append_tlv::<Self>(
+                acc,
+                fee_payer,
+                rent,
+                E::ext_type(),
+                E::ALIGN,
+                ExtensionState::Initialized,
+                Some(authority),
+                extension.pack(),
+            )
+        }
+    }
+
+    /// moves rent for count future E extensions into the account now without
+    /// growing it, the adds that follow take it before asking fee_payer for more
+    ///
+    /// # Safety
+    ///
+    /// Only lamports move, but acc's owner is read without a borrow and must
+    /// not be reassigned while the call runs.
+    unsafe fn prefund_extensions<E: Extension>(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        count: u8,
+    ) -> ProgramResult {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
         }
 
-        let mut ext_data_cursor = Self::len() + Self::EXT_START_MARKER.len();
+        let rent = Rent::from_account_info(rent)?;
+        let lamports = count as u64 * rent.minimum_balance(E::ext_with_meta_len());
+
+        log!("Prefund Extensions : {} lamports: {}", count, lamports);
 
-        while ext_data_cursor < data_len {
-            let ext_position = ext_data_cursor;
-            let read_ext_type = data[ext_data_cursor];
-            ext_data_cursor += 1;
+        Transfer {
+            from: fee_payer,
+            to: acc,
+            lamports,
+        }
+        .invoke()
+        .map_err(borrow_failed::<Self>)
+    }
 
-            let ext_state = ExtensionState::from_u8(data[ext_data_cursor])?;
+    /// for accounts smaller than the base state: writes the base state and then
+    /// adds the first extension
+    ///
+    /// # Safety
+    ///
+    /// acc's data is reallocated twice, for the base state and for the TLV. no
+    /// reference into acc's data may be alive across the call and acc's owner
+    /// must not be reassigned while it runs.
+    unsafe fn init_base_then_add_extension<E: Extension>(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        base_state: &[u8],
+        extension: &E,
+    ) -> ProgramResult {
+        extension.validate()?;
 
-            ext_data_cursor += 1;
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
 
-            let ext_len: Option<u16> = data
-                .get(ext_data_cursor..(ext_data_cursor + 2))
-                .map(|d| d.try_into().ok().map(|d| u16::from_le_bytes(d)))
-                .flatten();
+        if base_state.len() != Self::len() {
+            return Err(ProgramError::InvalidArgument);
+        }
 
-            match ext_len {
-                Some(ext_len) => {
-                    ext_data_cursor += 2;
+        let data_len = acc.data_len();
 
-                    let ext = unsafe {
-                        E::unpack(&data[ext_data_cursor..(ext_data_cursor + ext_len as usize)]).ok()
-                    };
+        if data_len >= Self::len() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
 
-                    ext_data_cursor += ext_len as usize;
+        grow_account::<Self>(acc, fee_payer, rent, Self::len() - data_len)?;
 
-                    if let Some(ext) = ext {
-                        if read_ext_type == ext_type.as_u8() {
-                            return Some(ExtensionInfo {
-                                ext,
-                                position: ext_position,
-                                state: ext_state,
-                            });
-                        }
-                    }
-                }
-                None => break,
-            }
+        {
+            let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<Self>)?;
+            unsafe { sol_memcpy(&mut data, base_state, base_state.len()) };
         }
 
-        None
+        unsafe { Self::add_extension(acc, fee_payer, rent, extension) }
+    }
+
+    /// creates new_acc owned by OWNER_PROGRAM with base_state followed by region,
+    /// the marker and TLVs as export_extension_region returns them (empty for a
+    /// base-only account), in one CreateAccount. pinocchio CPIs do not take the
+    /// system program account, so none is passed here
+    ///
+    /// # Safety
+    ///
+    /// new_acc must not be borrowed while the call runs, its data is written
+    /// right after the CreateAccount CPI.
+    unsafe fn create_with_extensions(
+        payer: &AccountInfo,
+        new_acc: &AccountInfo,
+        rent: &AccountInfo,
+        base_state: &[u8],
+        region: &[u8],
+    ) -> ProgramResult {
+        if base_state.len() != Self::len() {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // the region must walk cleanly to its end before anything is created
+        if !region.is_empty() {
+            check_region::<Self>(region)?;
+        }
+
+        let space = Self::len() + region.len();
+
+        if space > MAX_PERMITTED_DATA_LENGTH {
+            return Err(Self::to_program_error(
+                StateExtensionError::AccountSizeLimitExceeded,
+            ));
+        }
+
+        let rent = Rent::from_account_info(rent)?;
+
+        CreateAccount {
+            from: payer,
+            to: new_acc,
+            lamports: rent.minimum_balance(space),
+            space: space as u64,
+            owner: &Self::OWNER_PROGRAM,
+        }
+        .invoke()
+        .map_err(borrow_failed::<Self>)?;
+
+        log!("Create With Extensions : {} bytes", space);
+
+        let mut data = new_acc
+            .try_borrow_mut_data()
+            .map_err(borrow_failed::<Self>)?;
+
+        if data.len() != space {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        data[..Self::len()].copy_from_slice(base_state);
+        data[Self::len()..].copy_from_slice(region);
+
+        Ok(())
+    }
+
+    /// allocates a zeroed TLV marked Zerod, to be filled by reinitialize_extension
+    ///
+    /// # Safety
+    ///
+    /// acc's data may be reallocated and written, so no reference from
+    /// get_extension or the other detached readers of acc may be alive across
+    /// the call. acc's owner must not be reassigned while it runs.
+    unsafe fn reserve_extension<E: Extension>(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+    ) -> ProgramResult {
+        unsafe {
+            append_tlv::<Self>(
+                acc,
+                fee_payer,
+                rent,
+                E::ext_type(),
+                E::ALIGN,
+                ExtensionState::Zerod,
+                None,
+                &vec![0; E::ext_len() as usize],
+            )
+        }
+    }
+
+    /// reserves a zeroed TLV for E and hands out its payload to be written in
+    /// place, without building the extension first
+    ///
+    /// # Safety
+    ///
+    /// The returned writer holds a mutable borrow of acc's data, but references
+    /// from get_extension taken before the call point at data that has since
+    /// been reallocated and must not be used.
+    unsafe fn extension_writer<'a, E: Extension>(
+        acc: &'a AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+    ) -> Result<ExtensionWriter<'a, E>, ProgramError> {
+        let position = unsafe {
+            reserve_tlv::<Self>(
+                acc,
+                fee_payer,
+                rent,
+                E::ext_type(),
+                E::ALIGN,
+                ExtensionState::Zerod,
+                None,
+                E::ext_len() as usize,
+            )?
+        };
+
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<Self>)?;
+        let payload_start = payload_start(&data, position);
+
+        unsafe { sol_memset(&mut data[payload_start..], 0, E::ext_len() as usize) };
+
+        Ok(ExtensionWriter {
+            data,
+            position,
+            payload_start,
+            _ext: core::marker::PhantomData,
+        })
+    }
+
+    /// adds E with the payload written by fill into the zeroed reserved bytes,
+    /// validated once fill returns
+    ///
+    /// # Safety
+    ///
+    /// fill gets the zeroed payload of a TLV that has just been reserved and
+    /// must not touch acc itself. otherwise the same as add_extension: no
+    /// detached reference to acc's data may be held across the call.
+    unsafe fn add_extension_with<E: Extension, F: FnOnce(&mut [u8])>(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        fill: F,
+    ) -> ProgramResult {
+        let mut writer = unsafe { Self::extension_writer::<E>(acc, fee_payer, rent)? };
+
+        fill(writer.payload());
+
+        writer.finish()
+    }
+
+    /// adds every member of a group as (ext_type, payload). all members are checked
+    /// before the first is written: the set must be exactly one group, none may be
+    /// present yet and the total may not go past MAX_EXTENSIONS
+    ///
+    /// # Safety
+    ///
+    /// Every member is appended in turn, so acc's data is reallocated several
+    /// times and no detached reference into it may be alive across the call.
+    /// acc's owner must not be reassigned while it runs.
+    unsafe fn add_extension_group(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        members: &[(u8, &[u8])],
+    ) -> ProgramResult {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        let group = members
+            .first()
+            .and_then(|(ext_type, _)| Self::extension_group(*ext_type))
+            .ok_or(ProgramError::InvalidArgument)?;
+
+        if members.len() != group.len()
+            || group
+                .iter()
+                .any(|t| members.iter().filter(|(m, _)| m == t).count() != 1)
+        {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        if members
+            .iter()
+            .any(|(_, payload)| payload.len() > u16::MAX as usize)
+        {
+            return Err(Self::to_program_error(
+                StateExtensionError::ExtensionTooLarge,
+            ));
+        }
+
+        {
+            let data = acc.try_borrow_data().map_err(borrow_failed::<Self>)?;
+
+            let existing: Vec<u8> = Self::tlv_iter(&data)
+                .into_iter()
+                .flatten()
+                .map(|tlv| tlv.ext_type)
+                .collect();
+
+            if existing.iter().any(|t| group.contains(t)) {
+                return Err(Self::to_program_error(
+                    StateExtensionError::DuplicateExtension,
+                ));
+            }
+
+            if existing.len() + members.len() > Self::MAX_EXTENSIONS as usize {
+                return Err(Self::to_program_error(
+                    StateExtensionError::TooManyExtensions,
+                ));
+            }
+        }
+
+        log!("Add Extension Group : {}", members.len());
+
+        for (ext_type, payload) in members {
+            unsafe {
+                append_tlv::<Self>(
+                    acc,
+                    fee_payer,
+                    rent,
+                    *ext_type,
+                    1,
+                    ExtensionState::Initialized,
+                    None,
+                    payload,
+                )?
+            };
+        }
+
+        Ok(())
+    }
+
+    /// removes every member of ext_type's group, all of which must be present and
+    /// removable before the first goes
+    ///
+    /// # Safety
+    ///
+    /// Every member's TLV is removed and the later TLVs move, so references
+    /// from get_extension on acc are left pointing at other bytes or past the
+    /// end and must not be used after the call.
+    unsafe fn remove_extension_group(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        ext_type: u8,
+    ) -> ProgramResult {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        let group = Self::extension_group(ext_type).ok_or(ProgramError::InvalidArgument)?;
+
+        {
+            let data = acc.try_borrow_data().map_err(borrow_failed::<Self>)?;
+
+            for member in group {
+                let tlv = Self::find_tlv(&data, *member).ok_or(Self::to_program_error(
+                    StateExtensionError::ExtensionNotFound,
+                ))?;
+
+                if !Self::is_removable(*member) {
+                    return Err(Self::to_program_error(
+                        StateExtensionError::ExtensionNotRemovable,
+                    ));
+                }
+
+                check_authority(&data, tlv.position, None)?;
+            }
+        }
+
+        log!("Remove Extension Group : {}", group.len());
+
+        for member in group {
+            unsafe { remove_tlv::<Self>(acc, fee_payer, rent, *member, false, None)? };
+        }
+
+        Ok(())
+    }
+
+    /// stores the payload run length coded behind a [flag u8][uncompressed len u16]
+    /// prefix, flag 1 when coded and 0 when coding would not make it smaller.
+    /// coding and decoding are a single pass over the bytes, but on BPF that is
+    /// still several CUs per byte, so this suits large repetitive blobs only
+    ///
+    /// # Safety
+    ///
+    /// acc's data may be reallocated and written, so no reference from
+    /// get_extension or the other detached readers of acc may be alive across
+    /// the call. acc's owner must not be reassigned while it runs.
+    #[cfg(feature = "compression")]
+    unsafe fn add_extension_compressed<V: ExtensionEnum>(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        ext_type: V,
+        payload: &[u8],
+    ) -> ProgramResult {
+        if payload.len() > u16::MAX as usize {
+            return Err(Self::to_program_error(
+                StateExtensionError::ExtensionTooLarge,
+            ));
+        }
+
+        let encoded = rle_encode(payload);
+
+        let (flag, body) = if encoded.len() < payload.len() {
+            (1, encoded.as_slice())
+        } else {
+            (0, payload)
+        };
+
+        let mut stored = Vec::with_capacity(3 + body.len());
+        stored.push(flag);
+        stored.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        stored.extend_from_slice(body);
+
+        unsafe {
+            append_tlv::<Self>(
+                acc,
+                fee_payer,
+                rent,
+                ext_type.as_u8(),
+                1,
+                ExtensionState::Initialized,
+                None,
+                &stored,
+            )
+        }
+    }
+
+    // payload written by add_extension_compressed, restored
+    #[cfg(feature = "compression")]
+    fn get_extension_decompressed<V: ExtensionEnum>(
+        data: &[u8],
+        ext_type: V,
+    ) -> Result<Option<Vec<u8>>, ProgramError> {
+        let Some(tlv) = Self::find_tlv(data, ext_type.as_u8()) else {
+            return Ok(None);
+        };
+
+        let (prefix, body) = tlv
+            .payload
+            .split_at_checked(3)
+            .ok_or(ProgramError::InvalidAccountData)?;
+
+        let len = u16::from_le_bytes([prefix[1], prefix[2]]) as usize;
+
+        let payload = match prefix[0] {
+            0 => body.to_vec(),
+            1 => rle_decode(body, len)?,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+
+        if payload.len() != len {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(Some(payload))
+    }
+
+    /// moves the TLV behind the last one; the account size does not change. the
+    /// TLVs are padded again for their new addresses as in sort_extensions_by,
+    /// nothing is written if that changes the region size. refuses TLVs added
+    /// with an authority, see touch_extension_authorized
+    ///
+    /// # Safety
+    ///
+    /// The TLV is moved behind the others and every later TLV shifts left, any
+    /// reference from get_extension on acc must be dropped before the call.
+    unsafe fn touch_extension<E: Extension>(
+        acc: &AccountInfo,
+        ext_type: E::ExtensionEnum,
+    ) -> ProgramResult {
+        unsafe { touch_tlv::<Self>(acc, ext_type.as_u8(), None) }
+    }
+
+    /// authority must be a signer matching the pubkey stored with the TLV
+    ///
+    /// # Safety
+    ///
+    /// As touch_extension, references from get_extension on acc must be
+    /// dropped before the call.
+    unsafe fn touch_extension_authorized<E: Extension>(
+        acc: &AccountInfo,
+        authority: &AccountInfo,
+        ext_type: E::ExtensionEnum,
+    ) -> ProgramResult {
+        unsafe { touch_tlv::<Self>(acc, ext_type.as_u8(), Some(authority)) }
+    }
+
+    /// removes the TLV (and the marker with the last one) and refunds the freed rent
+    ///
+    /// # Safety
+    ///
+    /// The later TLVs are moved left and the account shrinks, references from
+    /// get_extension on acc must not be used after the call. acc's owner must
+    /// not be reassigned while it runs.
+    unsafe fn remove_extension<E: Extension>(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        ext_type: E::ExtensionEnum,
+    ) -> ProgramResult {
+        unsafe { remove_tlv::<Self>(acc, fee_payer, rent, ext_type.as_u8(), false, None) }
+    }
+
+    /// like remove_extension, but the removed bytes and the vacated tail are
+    /// zeroed before the account shrinks
+    ///
+    /// # Safety
+    ///
+    /// As remove_extension, references into the removed or moved bytes must not
+    /// outlive the call.
+    unsafe fn remove_extension_zeroize<E: Extension>(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        ext_type: E::ExtensionEnum,
+    ) -> ProgramResult {
+        unsafe { remove_tlv::<Self>(acc, fee_payer, rent, ext_type.as_u8(), true, None) }
+    }
+
+    /// authority must be a signer matching the pubkey stored with the TLV
+    ///
+    /// # Safety
+    ///
+    /// As remove_extension, references into the removed or moved bytes must not
+    /// outlive the call.
+    unsafe fn remove_extension_authorized<E: Extension>(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        authority: &AccountInfo,
+        ext_type: E::ExtensionEnum,
+    ) -> ProgramResult {
+        unsafe {
+            remove_tlv::<Self>(
+                acc,
+                fee_payer,
+                rent,
+                ext_type.as_u8(),
+                false,
+                Some(authority),
+            )
+        }
+    }
+
+    /// refuses TLVs added with an authority, see zero_out_extension_data_authorized
+    ///
+    /// # Safety
+    ///
+    /// The payload is overwritten in place: no reference to it from
+    /// get_extension may be used while the call runs, and acc's owner must not
+    /// be reassigned meanwhile.
+    unsafe fn zero_out_extension_data<E: Extension>(
+        acc: &AccountInfo,
+        ext_type: E::ExtensionEnum,
+    ) -> ProgramResult {
+        unsafe { zero_out_tlv::<Self, E>(acc, ext_type, None) }
+    }
+
+    /// authority must be a signer matching the pubkey stored with the TLV
+    ///
+    /// # Safety
+    ///
+    /// As zero_out_extension_data, no detached reference to the payload may be
+    /// read while it is overwritten.
+    unsafe fn zero_out_extension_data_authorized<E: Extension>(
+        acc: &AccountInfo,
+        authority: &AccountInfo,
+        ext_type: E::ExtensionEnum,
+    ) -> ProgramResult {
+        unsafe { zero_out_tlv::<Self, E>(acc, ext_type, Some(authority)) }
+    }
+
+    /// refuses TLVs added with an authority, see update_extension_authorized
+    ///
+    /// # Safety
+    ///
+    /// The payload is overwritten in place. a reference to it from
+    /// get_extension must not be read while the call runs, and values read
+    /// through it before the call are stale afterwards.
+    unsafe fn update_extension<E: Extension>(
+        acc: &AccountInfo,
+        ext_type: E::ExtensionEnum,
+        extension: &E,
+    ) -> ProgramResult {
+        unsafe { update_tlv::<Self, E>(acc, ext_type, extension, None) }
+    }
+
+    /// authority must be a signer matching the pubkey stored with the TLV
+    ///
+    /// # Safety
+    ///
+    /// As update_extension, no detached reference to the payload may be read
+    /// while it is overwritten.
+    unsafe fn update_extension_authorized<E: Extension>(
+        acc: &AccountInfo,
+        authority: &AccountInfo,
+        ext_type: E::ExtensionEnum,
+        extension: &E,
+    ) -> ProgramResult {
+        unsafe { update_tlv::<Self, E>(acc, ext_type, extension, Some(authority)) }
+    }
+
+    /// rewrites only the state of the TLV, leaving the payload as is
+    ///
+    /// # Safety
+    ///
+    /// The state byte is rewritten in place, ExtensionInfo values taken before
+    /// the call keep the old state. acc's owner must not be reassigned while it
+    /// runs.
+    unsafe fn set_extension_state<E: Extension>(
+        acc: &AccountInfo,
+        ext_type: E::ExtensionEnum,
+        new_state: ExtensionState,
+    ) -> ProgramResult {
+        log!(
+            "Set Extension State : {} {}",
+            E::ext_type(),
+            new_state.as_u8()
+        );
+
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<Self>)?;
+
+        let position = Self::find_tlv(&data, ext_type.as_u8())
+            .ok_or(Self::to_program_error(
+                StateExtensionError::ExtensionNotFound,
+            ))?
+            .position;
+
+        check_authority(&data, position, None)?;
+
+        let state = read_state(&data, position).ok_or(ProgramError::InvalidAccountData)?;
+
+        if !E::is_valid_transition(&state, &new_state) {
+            return Err(Self::to_program_error(
+                StateExtensionError::InvalidStateTransition,
+            ));
+        }
+
+        set_state_byte(&mut data, position, new_state);
+
+        Ok(())
+    }
+
+    /// fills a reserved or zeroed extension and marks it Initialized, the
+    /// extension has to be present
+    ///
+    /// # Safety
+    ///
+    /// The payload is overwritten in place, no detached reference to it may be
+    /// read while the call runs.
+    unsafe fn reinitialize_extension<E: Extension>(
+        acc: &AccountInfo,
+        ext_type: E::ExtensionEnum,
+        extension: &E,
+    ) -> ProgramResult {
+        log!("Reinitialize Extension : {}", E::ext_type());
+
+        extension.validate()?;
+
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<Self>)?;
+
+        let Some((position, state)) =
+            Self::get_extension_from_acc_data_unchecked::<E>(&data, ext_type)
+                .map(|info| (info.position, info.state))
+        else {
+            return Err(Self::to_program_error(
+                StateExtensionError::ExtensionNotFound,
+            ));
+        };
+
+        if state != ExtensionState::Zerod {
+            return Err(Self::to_program_error(
+                StateExtensionError::ExtensionDataAlreadyInitialized,
+            ));
+        }
+
+        check_authority(&data, position, None)?;
+
+        set_state_byte(&mut data, position, ExtensionState::Initialized);
+
+        let ext_data_start = payload_start(&data, position);
+
+        if let Some(data) = data.get_mut(ext_data_start..) {
+            unsafe { sol_memcpy(data, extension.pack(), E::ext_len() as usize) };
+        }
+
+        Ok(())
+    }
+
+    /// rewrites an extension with a payload of a different length, moving the
+    /// following TLVs and resizing the account; rent for freed bytes goes back
+    /// to fee_payer
+    ///
+    /// # Safety
+    ///
+    /// The TLV changes length and every later TLV moves, so no reference from
+    /// get_extension on acc may be alive across the call. acc's owner must not
+    /// be reassigned while it runs.
+    unsafe fn update_variable_extension<V: ExtensionEnum>(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        ext_type: V,
+        payload: &[u8],
+    ) -> ProgramResult {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        if payload.len() > u16::MAX as usize {
+            return Err(Self::to_program_error(
+                StateExtensionError::ExtensionTooLarge,
+            ));
+        }
+
+        check_payload_len::<Self>(ext_type.as_u8(), payload.len())?;
+
+        let (position, payload_start, old_len, state) = {
+            let data = acc.try_borrow_data().map_err(borrow_failed::<Self>)?;
+            let tlv = Self::find_tlv(&data, ext_type.as_u8()).ok_or(Self::to_program_error(
+                StateExtensionError::ExtensionNotFound,
+            ))?;
+
+            check_authority(&data, tlv.position, None)?;
+
+            (
+                tlv.position,
+                tlv.payload_start(),
+                tlv.len,
+                read_state(&data, tlv.position),
+            )
+        };
+
+        if state != Some(ExtensionState::Initialized) {
+            return Err(Self::to_program_error(
+                StateExtensionError::ExtensionDataIsNotInitialized,
+            ));
+        }
+
+        let refunded = unsafe {
+            resize_region::<Self>(
+                acc,
+                fee_payer,
+                rent,
+                payload_start,
+                old_len as usize,
+                payload.len(),
+                false,
+            )?
+        };
+
+        log!(
+            "Mutate Extension : {} len: {} refunded: {}",
+            ext_type.as_u8(),
+            payload.len(),
+            refunded
+        );
+
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<Self>)?;
+
+        data[position + 2..position + EXT_META_LEN]
+            .copy_from_slice(&(payload.len() as u16).to_le_bytes());
+
+        match data.get_mut(payload_start..) {
+            Some(data) => unsafe { sol_memcpy(data, payload, payload.len()) },
+            None => return Err(ProgramError::InvalidAccountData),
+        }
+
+        Ok(())
+    }
+
+    /// puts new_ext where the old_type TLV is, growing or shrinking the account by
+    /// the size difference with a single transfer or refund. later TLVs move by
+    /// that difference and keep their order
+    ///
+    /// # Safety
+    ///
+    /// The TLV is rewritten with a new layout and the later TLVs move by the
+    /// size difference, references from get_extension on acc must not be used
+    /// after the call.
+    unsafe fn replace_extension<Old: Extension, New: Extension>(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        old_type: Old::ExtensionEnum,
+        new_ext: &New,
+    ) -> ProgramResult {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        new_ext.validate()?;
+        check_payload_len::<Self>(New::ext_type(), New::LEN as usize)?;
+
+        if !New::ALIGN.is_power_of_two() || New::ALIGN > MAX_EXT_ALIGN {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let (position, old_span, padding) =
+            {
+                let data = acc.try_borrow_data().map_err(borrow_failed::<Self>)?;
+                let tlv = Self::find_tlv(&data, old_type.as_u8()).ok_or(Self::to_program_error(
+                    StateExtensionError::ExtensionNotFound,
+                ))?;
+
+                if tlv.ext_type != New::ext_type() && !Self::is_removable(tlv.ext_type) {
+                    return Err(Self::to_program_error(
+                        StateExtensionError::ExtensionNotRemovable,
+                    ));
+                }
+
+                if Self::tlv_iter(&data).into_iter().flatten().any(|other| {
+                    other.ext_type == New::ext_type() && other.position != tlv.position
+                }) {
+                    return Err(Self::to_program_error(
+                        StateExtensionError::DuplicateExtension,
+                    ));
+                }
+
+                check_authority(&data, tlv.position, None)?;
+
+                let unaligned_payload_addr = data.as_ptr() as usize + tlv.position + EXT_META_LEN;
+                let padding =
+                    unaligned_payload_addr.next_multiple_of(New::ALIGN) - unaligned_payload_addr;
+
+                (tlv.position, tlv.end() - tlv.position, padding)
+            };
+
+        let new_span = EXT_META_LEN + padding + New::LEN as usize;
+
+        let refunded = unsafe {
+            resize_region::<Self>(acc, fee_payer, rent, position, old_span, new_span, false)?
+        };
+
+        log!(
+            "Replace Extension : {} with: {} refunded: {}",
+            old_type.as_u8(),
+            New::ext_type(),
+            refunded
+        );
+
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<Self>)?;
+
+        write_tlv_header(
+            &mut data[position..position + EXT_META_LEN + padding],
+            New::ext_type(),
+            ExtensionState::Initialized,
+            None,
+            New::LEN as usize,
+        );
+
+        let payload_start = position + EXT_META_LEN + padding;
+
+        unsafe {
+            sol_memcpy(
+                &mut data[payload_start..],
+                new_ext.pack(),
+                New::LEN as usize,
+            )
+        };
+
+        Ok(())
+    }
+
+    /// drops the end of the payload so it is new_len bytes long, later TLVs move
+    /// left and the freed rent goes back to fee_payer. the kept prefix is untouched
+    ///
+    /// # Safety
+    ///
+    /// The payload shrinks and the later TLVs move left, references from
+    /// get_extension on acc must not be used after the call.
+    unsafe fn truncate_extension<V: ExtensionEnum>(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        ext_type: V,
+        new_len: u16,
+    ) -> ProgramResult {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        check_payload_len::<Self>(ext_type.as_u8(), new_len as usize)?;
+
+        let (position, payload_start, old_len, state) = {
+            let data = acc.try_borrow_data().map_err(borrow_failed::<Self>)?;
+            let tlv = Self::find_tlv(&data, ext_type.as_u8()).ok_or(Self::to_program_error(
+                StateExtensionError::ExtensionNotFound,
+            ))?;
+
+            check_authority(&data, tlv.position, None)?;
+
+            (
+                tlv.position,
+                tlv.payload_start(),
+                tlv.len,
+                read_state(&data, tlv.position),
+            )
+        };
+
+        if state != Some(ExtensionState::Initialized) {
+            return Err(Self::to_program_error(
+                StateExtensionError::ExtensionDataIsNotInitialized,
+            ));
+        }
+
+        if new_len >= old_len {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let refunded = unsafe {
+            resize_region::<Self>(
+                acc,
+                fee_payer,
+                rent,
+                payload_start + new_len as usize,
+                (old_len - new_len) as usize,
+                0,
+                false,
+            )?
+        };
+
+        log!(
+            "Truncate Extension : {} len: {} refunded: {}",
+            ext_type.as_u8(),
+            new_len,
+            refunded
+        );
+
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<Self>)?;
+
+        data[position + 2..position + EXT_META_LEN].copy_from_slice(&new_len.to_le_bytes());
+
+        Ok(())
+    }
+
+    /// exchanges the payloads of two Initialized TLVs that both store E::LEN bytes,
+    /// the TLVs themselves stay where they are
+    ///
+    /// # Safety
+    ///
+    /// Both payloads are overwritten in place, detached references to either of
+    /// them must not be read while the call runs.
+    unsafe fn swap_payloads<E: Extension>(
+        acc: &AccountInfo,
+        type_a: E::ExtensionEnum,
+        type_b: E::ExtensionEnum,
+    ) -> ProgramResult {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<Self>)?;
+
+        let ext_types = [type_a.as_u8(), type_b.as_u8()];
+        let mut payload_starts = [0; 2];
+
+        for (ext_type, payload_start) in ext_types.iter().zip(payload_starts.iter_mut()) {
+            let tlv = Self::find_tlv(&data, *ext_type).ok_or(Self::to_program_error(
+                StateExtensionError::ExtensionNotFound,
+            ))?;
+
+            check_authority(&data, tlv.position, None)?;
+
+            if read_state(&data, tlv.position) != Some(ExtensionState::Initialized) {
+                return Err(Self::to_program_error(
+                    StateExtensionError::ExtensionDataIsNotInitialized,
+                ));
+            }
+
+            if tlv.len != E::LEN {
+                return Err(Self::to_program_error(
+                    StateExtensionError::ExtensionLengthMismatch,
+                ));
+            }
+
+            *payload_start = tlv.payload_start();
+        }
+
+        let [a, b] = payload_starts;
+
+        if a == b {
+            return Ok(());
+        }
+
+        log!("Swap Payloads : {} {}", ext_types[0], ext_types[1]);
+
+        let (low, high) = data.split_at_mut(a.max(b));
+
+        low[a.min(b)..a.min(b) + E::LEN as usize].swap_with_slice(&mut high[..E::LEN as usize]);
+
+        Ok(())
+    }
+
+    /// applies every (ext_type, payload) update under a single borrow, nothing is
+    /// written unless all updates are valid and every targeted type is present
+    ///
+    /// # Safety
+    ///
+    /// Every targeted payload is overwritten in place, no detached reference
+    /// into acc's data may be read while the call runs.
+    unsafe fn update_extensions(acc: &AccountInfo, updates: &[(u8, &[u8])]) -> ProgramResult {
+        log!("Mutate Extensions : {}", updates.len());
+
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<Self>)?;
+
+        let headers: Vec<(usize, usize, u8, Option<ExtensionState>, u16)> = Self::tlv_iter(&data)
+            .ok_or(ProgramError::InvalidAccountData)?
+            .map(|tlv| {
+                (
+                    tlv.position,
+                    tlv.payload_start(),
+                    tlv.ext_type,
+                    read_state(&data, tlv.position),
+                    tlv.len,
+                )
+            })
+            .collect();
+
+        let mut targets = Vec::with_capacity(updates.len());
+
+        for (ext_type, payload) in updates {
+            let Some((position, payload_start, _, state, len)) =
+                headers.iter().find(|(_, _, t, _, _)| t == ext_type)
+            else {
+                return Err(Self::to_program_error(
+                    StateExtensionError::ExtensionNotFound,
+                ));
+            };
+
+            check_authority(&data, *position, None)?;
+
+            if *state != Some(ExtensionState::Initialized) {
+                return Err(Self::to_program_error(
+                    StateExtensionError::ExtensionDataIsNotInitialized,
+                ));
+            }
+
+            if *len as usize != payload.len() {
+                return Err(Self::to_program_error(
+                    StateExtensionError::ExtensionLengthMismatch,
+                ));
+            }
+
+            targets.push((*payload_start, *payload));
+        }
+
+        for (payload_start, payload) in targets {
+            match data.get_mut(payload_start..) {
+                Some(data) => unsafe { sol_memcpy(data, payload, payload.len()) },
+                None => return Err(ProgramError::InvalidAccountData),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// wipes the payload of every Initialized TLV and marks it Zerod under one
+    /// borrow, returns how many were zeroed. nothing is written if any of them is
+    /// pinned or carries an authority
+    ///
+    /// # Safety
+    ///
+    /// Every Initialized payload is overwritten in place, no detached reference
+    /// into acc's data may be read while the call runs.
+    unsafe fn zero_all_extensions(acc: &AccountInfo) -> Result<u32, ProgramError> {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<Self>)?;
+
+        let targets: Vec<(usize, usize, u16)> = match Self::tlv_iter(&data) {
+            Some(tlvs) => tlvs
+                .filter(|tlv| {
+                    ExtensionState::from_u8(tlv.state) == Some(ExtensionState::Initialized)
+                })
+                .map(|tlv| (tlv.ext_type, tlv.position, tlv.payload_start(), tlv.len))
+                .map(|(ext_type, position, payload_start, len)| {
+                    if !Self::is_removable(ext_type) {
+                        return Err(Self::to_program_error(
+                            StateExtensionError::ExtensionNotRemovable,
+                        ));
+                    }
+
+                    check_authority(&data, position, None)?;
+
+                    Ok((position, payload_start, len))
+                })
+                .collect::<Result<_, ProgramError>>()?,
+            None => Vec::new(),
+        };
+
+        for (position, payload_start, len) in &targets {
+            set_state_byte(&mut data, *position, ExtensionState::Zerod);
+            unsafe { sol_memset(&mut data[*payload_start..], 0, *len as usize) };
+        }
+
+        log!("Zero All Extensions : {}", targets.len());
+
+        Ok(targets.len() as u32)
+    }
+
+    /// reorders the TLVs by ascending key(type, payload), ties keep their order and
+    /// free slots go last. padding is worked out again for each new position: a
+    /// padded TLV keeps the alignment its payload had, an unpadded one is taken
+    /// to need none. if that changes the region size nothing is written. a TLV
+    /// added with an authority may not change places, see
+    /// sort_extensions_by_authorized
+    ///
+    /// # Safety
+    ///
+    /// The whole extension region is rewritten in a new order, so every
+    /// reference from get_extension on acc points at a different TLV afterwards
+    /// and must be dropped before the call. key must not touch acc.
+    unsafe fn sort_extensions_by<F: Fn(u8, &[u8]) -> u64>(
+        acc: &AccountInfo,
+        key: F,
+    ) -> ProgramResult {
+        unsafe { sort_tlvs::<Self, F>(acc, key, None) }
+    }
+
+    /// authority must be a signer matching the pubkey stored with every TLV
+    /// that changes places
+    ///
+    /// # Safety
+    ///
+    /// As sort_extensions_by, references from get_extension on acc must be
+    /// dropped before the call and key must not touch acc.
+    unsafe fn sort_extensions_by_authorized<F: Fn(u8, &[u8]) -> u64>(
+        acc: &AccountInfo,
+        authority: &AccountInfo,
+        key: F,
+    ) -> ProgramResult {
+        unsafe { sort_tlvs::<Self, F>(acc, key, Some(authority)) }
+    }
+
+    /// rewrites every state that is not a known ExtensionState to Initialized, so
+    /// a TLV left behind by an external writer no longer stops the readers.
+    /// returns how many were repaired, nothing is written if one of them carries
+    /// an authority
+    ///
+    /// # Safety
+    ///
+    /// State bytes are rewritten in place, ExtensionInfo values taken before
+    /// the call keep the old states.
+    unsafe fn normalize_states(acc: &AccountInfo) -> Result<u32, ProgramError> {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<Self>)?;
+
+        let positions: Vec<usize> = match Self::tlv_iter(&data) {
+            Some(tlvs) => tlvs
+                .filter(|tlv| ExtensionState::from_u8(tlv.state).is_none())
+                .map(|tlv| check_authority(&data, tlv.position, None).map(|_| tlv.position))
+                .collect::<Result<_, ProgramError>>()?,
+            None => Vec::new(),
+        };
+
+        for position in &positions {
+            set_state_byte(&mut data, *position, ExtensionState::Initialized);
+        }
+
+        log!("Normalize States : {}", positions.len());
+
+        Ok(positions.len() as u32)
+    }
+
+    // variants in on-disk (insertion) order
+    fn get_extension_variants<V: ExtensionEnum>(acc: &AccountInfo) -> Option<Vec<V>> {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return None;
+        }
+
+        // length comes from the borrow itself so it is never stale after a realloc
+        let data = acc.try_borrow_data().ok()?;
+
+        if data.len() <= Self::len() {
+            return None;
+        }
+
+        Self::get_extension_variants_from_acc_data_uncheked(&data)
+    }
+
+    fn try_get_extension_variants<V: ExtensionEnum>(
+        acc: &AccountInfo,
+    ) -> Result<Vec<V>, ProgramError> {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        let data = acc.try_borrow_data()?;
+
+        if data.len() < Self::len() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if zero_after_base::<Self>(&data) {
+            return Ok(Vec::new());
+        }
+
+        Self::get_extension_variants_from_acc_data_uncheked(&data)
+            .ok_or(ProgramError::InvalidAccountData)
+    }
+
+    // skips the first `start` variants and returns at most `limit` of them. types
+    // V does not know are left out before paging, so they take up no page slot
+    fn get_extension_variants_paged<V: ExtensionEnum>(
+        acc: &AccountInfo,
+        start: usize,
+        limit: usize,
+    ) -> Option<Vec<V>> {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return None;
+        }
+
+        let data = acc.try_borrow_data().ok()?;
+
+        if data.len() <= Self::len() {
+            return None;
+        }
+
+        let extensions = Self::tlv_iter(&data)?
+            .filter_map(|tlv| V::from_u8(tlv.ext_type))
+            .skip(start)
+            .take(limit)
+            .collect();
+
+        Some(extensions)
+    }
+
+    fn get_extension_variants_from_acc_data_uncheked<V: ExtensionEnum>(
+        data: &[u8],
+    ) -> Option<Vec<V>> {
+        let extensions = Self::tlv_iter(data)?
+            .filter_map(|tlv| V::from_u8(tlv.ext_type))
+            .collect();
+
+        Some(extensions)
+    }
+
+    // self test for integration tests: every type get_extension_variants lists
+    // is found by find_tlv, and E is listed exactly when get_extension finds it.
+    // the two paths bound check differently, so corrupt data can split them
+    #[cfg(debug_assertions)]
+    fn audit_consistency<V: ExtensionEnum, E: Extension>(acc: &AccountInfo) -> bool {
+        let variants = Self::get_extension_variants::<V>(acc).unwrap_or_default();
+
+        {
+            let Ok(data) = acc.try_borrow_data() else {
+                return false;
+            };
+
+            if !variants
+                .iter()
+                .all(|v| Self::find_tlv(&data, v.as_u8()).is_some())
+            {
+                return false;
+            }
+        }
+
+        let Some(ext_type) = E::ExtensionEnum::from_u8(E::ext_type()) else {
+            return false;
+        };
+
+        let listed = variants.iter().any(|v| v.as_u8() == E::ext_type());
+        let found = unsafe { Self::get_extension::<E>(acc, ext_type) }.is_some();
+
+        listed == found
+    }
+
+    // like get_extension_variants_from_acc_data_uncheked, but a walk that stops
+    // on a malformed TLV is an error instead of a truncated list
+    fn try_get_extension_variants_from_data<V: ExtensionEnum>(
+        data: &[u8],
+    ) -> Result<Vec<V>, ProgramError> {
+        let mut tlvs = Self::tlv_iter(data).ok_or(ProgramError::InvalidAccountData)?;
+
+        let extensions = tlvs
+            .by_ref()
+            .filter_map(|tlv| V::from_u8(tlv.ext_type))
+            .collect();
+
+        if tlvs.is_malformed() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(extensions)
+    }
+
+    /// the slice is rebuilt from the borrow on every call, so a read after a
+    /// realloc sees the new length. the result lives as long as the &AccountInfo
+    /// but the data borrow is released before returning, StateView::get keeps
+    /// the borrow for as long as its references live
+    ///
+    /// # Safety
+    ///
+    /// The returned reference points into acc's data with no borrow held. no
+    /// mutable borrow of acc's data may exist while it is alive, and it must not
+    /// be read after anything writes, moves or resizes acc's data (a realloc or
+    /// any writer of this trait) or reassigns acc's owner. get the extension
+    /// again after such a call instead.
+    unsafe fn get_extension<'e, E: Extension>(
+        acc: &'e AccountInfo,
+        ext_type: E::ExtensionEnum,
+    ) -> Option<ExtensionInfo<'e, E>> {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return None;
+        }
+
+        // length comes from the borrow itself so it is never stale after a realloc
+        let data = acc.try_borrow_data().ok()?;
+
+        if data.len() < Self::len() + Self::EXT_START_MARKER.len() {
+            return None;
+        }
+
+        let data = unsafe { core::slice::from_raw_parts(data.as_ptr(), data.len()) };
+
+        Self::get_extension_from_acc_data_unchecked(data, ext_type)
+    }
+
+    /// get_extension hands back Zerod TLVs too, with their state set. this treats
+    /// a zeroed extension as not present: Some only for Initialized ones
+    ///
+    /// # Safety
+    ///
+    /// The same rule as get_extension: the result must not be alive under a
+    /// mutable borrow of acc's data, nor read after that data is written, moved
+    /// or resized.
+    unsafe fn get_live_extension<'e, E: Extension>(
+        acc: &'e AccountInfo,
+        ext_type: E::ExtensionEnum,
+    ) -> Option<ExtensionInfo<'e, E>> {
+        unsafe { Self::get_extension::<E>(acc, ext_type) }
+            .filter(|info| info.state == ExtensionState::Initialized)
+    }
+
+    /// copies the payload of the first TLV of the type into out, returns its length
+    ///
+    /// # Safety
+    ///
+    /// Reads acc's owner without a borrow, the owner must not be reassigned
+    /// while the call runs.
+    unsafe fn copy_extension_payload<V: ExtensionEnum>(
+        acc: &AccountInfo,
+        ext_type: V,
+        out: &mut [u8],
+    ) -> Result<usize, ProgramError> {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        let data = acc.try_borrow_data()?;
+
+        let payload = Self::find_tlv(&data, ext_type.as_u8())
+            .ok_or(Self::to_program_error(
+                StateExtensionError::ExtensionNotFound,
+            ))?
+            .payload;
+
+        let out = out
+            .get_mut(..payload.len())
+            .ok_or(ProgramError::InvalidArgument)?;
+
+        unsafe { sol_memcpy(out, payload, payload.len()) };
+
+        Ok(payload.len())
+    }
+
+    // payload of the first TLV of the type is exactly expected
+    fn extension_payload_eq<V: ExtensionEnum>(
+        acc: &AccountInfo,
+        ext_type: V,
+        expected: &[u8],
+    ) -> bool {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return false;
+        }
+
+        let Ok(data) = acc.try_borrow_data() else {
+            return false;
+        };
+
+        Self::find_tlv(&data, ext_type.as_u8()).is_some_and(|tlv| tlv.payload == expected)
+    }
+
+    /// get_extension for each account, in order
+    ///
+    /// # Safety
+    ///
+    /// Every entry follows the get_extension rule for the account it came from:
+    /// none may be read after that account's data is written, moved or resized.
+    unsafe fn get_extension_across<'e, E: Extension>(
+        accs: &'e [&AccountInfo],
+        ext_type: E::ExtensionEnum,
+    ) -> Vec<Option<ExtensionInfo<'e, E>>> {
+        accs.iter()
+            .map(|acc| unsafe { Self::get_extension(acc, ext_type.clone()) })
+            .collect()
+    }
+
+    /// like get_extension, but a matching TLV whose stored length is not E::LEN is
+    /// an ExtensionLengthMismatch instead of being skipped. the same rule about
+    /// holding the result across a resize applies
+    ///
+    /// # Safety
+    ///
+    /// The same rule as get_extension: the result must not be alive under a
+    /// mutable borrow of acc's data, nor read after that data is written, moved
+    /// or resized.
+    unsafe fn get_extension_strict<'e, E: Extension>(
+        acc: &'e AccountInfo,
+        ext_type: E::ExtensionEnum,
+    ) -> Result<Option<ExtensionInfo<'e, E>>, ProgramError> {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        let data = acc.try_borrow_data()?;
+
+        if data.len() <= Self::len() {
+            return Ok(None);
+        }
+
+        let data = unsafe { core::slice::from_raw_parts(data.as_ptr(), data.len()) };
+
+        let Some(tlv) = Self::find_tlv(data, ext_type.as_u8()) else {
+            return Ok(None);
+        };
+
+        if tlv.len != E::LEN {
+            return Err(Self::to_program_error(
+                StateExtensionError::ExtensionLengthMismatch,
+            ));
+        }
+
+        Ok(Some(ExtensionInfo {
+            ext: unsafe { E::unpack(tlv.payload)? },
+            position: tlv.position,
+            state: read_state(data, tlv.position).ok_or(ProgramError::InvalidAccountData)?,
+            len: tlv.len,
+        }))
+    }
+
+    // the first len bytes of base state, None if the account is not ours, the
+    // data is shorter than the base state or len reaches past it. held as a
+    // borrow so the bytes cannot change underneath the caller
+    fn base_discriminator(acc: &AccountInfo, len: usize) -> Option<Ref<'_, [u8]>> {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) || len > Self::len() {
+            return None;
+        }
+
+        let data = acc.try_borrow_data().ok()?;
+
+        if data.len() < Self::len() {
+            return None;
+        }
+
+        Some(Ref::map(data, |data| &data[..len]))
+    }
+
+    fn check_discriminator(acc: &AccountInfo, expected: &[u8]) -> bool {
+        Self::base_discriminator(acc, expected.len()).is_some_and(|d| *d == *expected)
+    }
+
+    /// hands f the base state and an extension from a single borrow, None if the
+    /// base does not fit in BASE_STATE_LEN or is misaligned, or the extension is absent
+    ///
+    /// # Safety
+    ///
+    /// B must be valid for any bit pattern of its size, the start of the base
+    /// state is read as a B in place. acc's owner must not be reassigned while
+    /// the call runs.
+    unsafe fn with_base_and_extension<B, E: Extension, F: FnOnce(&B, &E) -> R, R>(
+        acc: &AccountInfo,
+        ext_type: E::ExtensionEnum,
+        f: F,
+    ) -> Option<R> {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return None;
+        }
+
+        let data = acc.try_borrow_data().ok()?;
+
+        if core::mem::size_of::<B>() > Self::len()
+            || !(data.as_ptr() as usize).is_multiple_of(core::mem::align_of::<B>())
+        {
+            return None;
+        }
+
+        let info = Self::get_extension_from_acc_data_unchecked::<E>(&data, ext_type)?;
+        let base = unsafe { &*(data.as_ptr() as *const B) };
+
+        Some(f(base, info.ext))
+    }
+
+    fn get_extension_from_acc_data_unchecked<'e, E: Extension>(
+        data: &'e [u8],
+        ext_type: E::ExtensionEnum,
+    ) -> Option<ExtensionInfo<'e, E>> {
+        for tlv in Self::tlv_iter(data)? {
+            let ext_state = read_state(data, tlv.position)?;
+
+            if tlv.ext_type != ext_type.as_u8() {
+                continue;
+            }
+
+            if let Ok(ext) = unsafe { E::unpack(tlv.payload) } {
+                return Some(ExtensionInfo {
+                    ext,
+                    position: tlv.position,
+                    state: ext_state,
+                    len: tlv.len,
+                });
+            }
+        }
+
+        None
+    }
+
+    // like get_extension_from_acc_data_unchecked, but a matching TLV that fails to
+    // decode is an error instead of being skipped
+    fn try_get_extension_from_acc_data<'e, E: Extension>(
+        data: &'e [u8],
+        ext_type: E::ExtensionEnum,
+    ) -> Result<Option<ExtensionInfo<'e, E>>, ProgramError> {
+        if data.len() <= Self::len() {
+            return Ok(None);
+        }
+
+        let mut tlvs = Self::tlv_iter(data).ok_or(ProgramError::InvalidAccountData)?;
+
+        for tlv in tlvs.by_ref() {
+            if Self::STRICT_EXTENSION_TYPES && E::ExtensionEnum::from_u8(tlv.ext_type).is_none() {
+                return Err(Self::to_program_error(
+                    StateExtensionError::InvalidExtensionType,
+                ));
+            }
+
+            if tlv.ext_type != ext_type.as_u8() {
+                continue;
+            }
+
+            return Ok(Some(ExtensionInfo {
+                ext: unsafe { E::unpack(tlv.payload)? },
+                position: tlv.position,
+                state: read_state(data, tlv.position).ok_or(ProgramError::InvalidAccountData)?,
+                len: tlv.len,
+            }));
+        }
+
+        if tlvs.is_malformed() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(None)
+    }
+
+    // every TLV of E's type in order, each unpacked only when reached. entries
+    // that do not unpack as E are skipped and the walk stops at malformed data
+    fn typed_extensions<'a, E: Extension + 'a>(
+        data: &'a [u8],
+    ) -> impl Iterator<Item = ExtensionInfo<'a, E>> {
+        Self::tlv_iter(data)
+            .into_iter()
+            .flatten()
+            .filter(|tlv| tlv.ext_type == E::ext_type())
+            .filter_map(move |tlv| {
+                Some(ExtensionInfo {
+                    ext: unsafe { E::unpack(tlv.payload) }.ok()?,
+                    position: tlv.position,
+                    state: read_state(data, tlv.position)?,
+                    len: tlv.len,
+                })
+            })
+    }
+
+    // walks the TLVs following the marker, None if the marker is missing
+    fn tlv_iter(data: &[u8]) -> Option<TlvIter<'_>> {
+        let ext_marker_start = Self::len();
+        let ext_data_start = ext_marker_start + Self::EXT_START_MARKER.len();
+
+        if !Self::check_ext_marker(data.get(ext_marker_start..ext_data_start)?) {
+            return None;
+        }
+
+        Some(TlvIter::new(data, ext_data_start))
+    }
+
+    // raw (type byte, state byte, len) of every TLV, nothing is decoded
+    fn list_tlv_headers(data: &[u8]) -> Vec<(u8, u8, u16)> {
+        Self::tlv_iter(data)
+            .into_iter()
+            .flatten()
+            .map(|tlv| (tlv.ext_type, data[tlv.position + 1], tlv.len))
+            .collect()
+    }
+
+    fn enumerate_extension_types(data: &[u8]) -> Vec<u8> {
+        match Self::tlv_iter(data) {
+            Some(tlvs) => tlvs.map(|tlv| tlv.ext_type).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn has_unknown_extensions<V: ExtensionEnum>(acc: &AccountInfo) -> bool {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return false;
+        }
+
+        let Ok(data) = acc.try_borrow_data() else {
+            return false;
+        };
+
+        Self::enumerate_extension_types(&data)
+            .into_iter()
+            .any(|ext_type| V::from_u8(ext_type).is_none())
+    }
+
+    // every TLV starts where the previous one ends, so a length reaching into
+    // the next TLV leaves the walk reading a header out of the middle of it and
+    // running past the data. TLVs that do not tile the region that way are
+    // OverlappingExtensions, bytes that are neither the marker nor a zero tail
+    // behind the base state are InvalidAccountData
+    fn check_no_overlap(data: &[u8]) -> Result<(), ProgramError> {
+        if data.len() <= Self::len() || zero_after_base::<Self>(data) {
+            return Ok(());
+        }
+
+        let mut tlvs = Self::tlv_iter(data).ok_or(ProgramError::InvalidAccountData)?;
+
+        tlvs.by_ref().for_each(drop);
+
+        if tlvs.is_malformed() {
+            return Err(Self::to_program_error(
+                StateExtensionError::OverlappingExtensions,
+            ));
+        }
+
+        Ok(())
+    }
+
+    // owned, holding at least the base state and either no extension region yet
+    // or one that passes check_no_overlap
+    fn is_extensible(acc: &AccountInfo) -> bool {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return false;
+        }
+
+        let Ok(data) = acc.try_borrow_data() else {
+            return false;
+        };
+
+        data.len() >= Self::len() && Self::check_no_overlap(&data).is_ok()
+    }
+
+    // bytes a TLV of E takes on disk, before any ALIGN padding
+    fn tlv_size<E: Extension>() -> usize {
+        E::ext_with_meta_len()
+    }
+
+    // tlv_size plus the marker written along with the first extension
+    fn tlv_size_with_marker<E: Extension>() -> usize {
+        Self::EXT_START_MARKER.len() + Self::tlv_size::<E>()
+    }
+
+    // account size for base state followed by extensions of the given lengths.
+    // only covers plain TLVs: no ALIGN padding and no authority header, both
+    // depend on how each one was added and on where it landed, so for accounts
+    // holding those compare against the real data_len instead
+    fn expected_size_for(ext_lens: &[u16]) -> usize {
+        if ext_lens.is_empty() {
+            return Self::len();
+        }
+
+        Self::len()
+            + Self::EXT_START_MARKER.len()
+            + ext_lens
+                .iter()
+                .map(|len| EXT_META_LEN + *len as usize)
+                .sum::<usize>()
+    }
+
+    // same limits as expected_size_for; padded or authority TLVs never match
+    fn data_len_matches(acc: &AccountInfo, ext_lens: &[u16]) -> bool {
+        acc.data_len() == Self::expected_size_for(ext_lens)
+    }
+
+    /// reads the TLV starting at a known offset without walking from the marker
+    ///
+    /// # Safety
+    ///
+    /// E must meet the requirements of Extension::unpack, the payload at
+    /// position is reinterpreted as E.
+    unsafe fn get_extension_at<E: Extension>(
+        data: &[u8],
+        position: usize,
+    ) -> Option<ExtensionInfo<'_, E>> {
+        let ext_marker_start = Self::len();
+        let ext_data_start = ext_marker_start + Self::EXT_START_MARKER.len();
+
+        if position < ext_data_start
+            || !Self::check_ext_marker(data.get(ext_marker_start..ext_data_start)?)
+        {
+            return None;
+        }
+
+        let tlv = TlvIter::new(data, position).next()?;
+
+        if tlv.ext_type != E::ext_type() {
+            return None;
+        }
+
+        Some(ExtensionInfo {
+            ext: unsafe { E::unpack(tlv.payload).ok()? },
+            position,
+            state: read_state(data, tlv.position)?,
+            len: tlv.len,
+        })
+    }
+
+    // TLV found at a stored offset from the start of the extension region (the
+    // marker), for extensions that link to siblings. only offsets landing on the
+    // start of a well formed TLV resolve
+    fn resolve_offset_ref(data: &[u8], offset: usize) -> Option<(u8, ExtensionState, &[u8])> {
+        let position = Self::len().checked_add(offset)?;
+
+        let tlv = Self::tlv_iter(data)?
+            .take_while(|tlv| tlv.position <= position)
+            .find(|tlv| tlv.position == position)?;
+
+        Some((
+            tlv.ext_type,
+            ExtensionState::from_u8(tlv.state)?,
+            tlv.payload,
+        ))
+    }
+
+    // (type, state, position) of the TLV following the one at position, None
+    // if that one is the last or position is not the start of a TLV
+    fn next_extension_after(data: &[u8], position: usize) -> Option<(u8, ExtensionState, usize)> {
+        let mut tlvs = Self::tlv_iter(data)?.skip_while(|tlv| tlv.position != position);
+
+        tlvs.next()?;
+
+        let next = tlvs.next()?;
+
+        Some((
+            next.ext_type,
+            ExtensionState::from_u8(next.state)?,
+            next.position,
+        ))
+    }
+
+    // first byte add_extension would write: the marker right after the base
+    // state for a base-only account, else the new TLV header. appends go behind
+    // the last TLV, in front of a raw region or a zero tail
+    fn next_write_offset(acc: &AccountInfo) -> usize {
+        if let Ok(data) = acc.try_borrow_data()
+            && let Ok(end) = tlvs_end::<Self>(&data)
+        {
+            return end.max(Self::len());
+        }
+
+        acc.data_len().max(Self::len())
+    }
+
+    /// appends bytes behind the TLVs as a region the walk stops in front of, e.g. a
+    /// signature buffer. it is headed like a TLV with the EXT_STATE_RAW state, so
+    /// it holds at most u16::MAX bytes, and later TLVs are inserted before it.
+    /// one per account
+    ///
+    /// # Safety
+    ///
+    /// acc's data may be reallocated and written, so no reference from
+    /// get_extension or the other detached readers of acc may be alive across
+    /// the call. acc's owner must not be reassigned while it runs.
+    unsafe fn append_raw_region(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        bytes: &[u8],
+    ) -> ProgramResult {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        if bytes.len() > u16::MAX as usize {
+            return Err(Self::to_program_error(
+                StateExtensionError::ExtensionTooLarge,
+            ));
+        }
+
+        let data_len = {
+            let data = acc.try_borrow_data().map_err(borrow_failed::<Self>)?;
+
+            if data.len() < Self::len() {
+                return Err(Self::to_program_error(
+                    StateExtensionError::BaseStateNotInitialized,
+                ));
+            }
+
+            if Self::raw_region_header(&data).is_some() {
+                return Err(ProgramError::InvalidArgument);
+            }
+
+            data.len()
+        };
+
+        // a zero tail is taken up first, as in add_extension
+        let at = tlvs_end::<Self>(&acc.try_borrow_data().map_err(borrow_failed::<Self>)?)?;
+
+        let marker_len = if at == Self::len() {
+            Self::EXT_START_MARKER.len()
+        } else {
+            0
+        };
+        let region_len = marker_len + EXT_META_LEN + bytes.len();
+
+        let lamports = match (at + region_len).saturating_sub(data_len) {
+            0 => 0,
+            shortfall => grow_account::<Self>(acc, fee_payer, rent, shortfall)?,
+        };
+
+        log!("Append Raw Region : {} lamports: {}", bytes.len(), lamports);
+
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<Self>)?;
+        let (marker, region) = data[at..at + region_len].split_at_mut(marker_len);
+
+        marker.copy_from_slice(&Self::EXT_START_MARKER[..marker_len]);
+
+        region[0] = 0;
+        region[1] = EXT_STATE_RAW;
+        region[2..EXT_META_LEN].copy_from_slice(&(bytes.len() as u16).to_le_bytes());
+        region[EXT_META_LEN..].copy_from_slice(bytes);
+
+        Ok(())
+    }
+
+    // the raw region written by append_raw_region, None unless there is one of
+    // exactly region_len bytes
+    fn raw_region(acc: &AccountInfo, region_len: usize) -> Option<Ref<'_, [u8]>> {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return None;
+        }
+
+        let data = acc.try_borrow_data().ok()?;
+        let start = Self::raw_region_header(&data)? + EXT_META_LEN;
+        let len = u16::from_le_bytes([data[start - 2], data[start - 1]]) as usize;
+
+        if len != region_len || start + len > data.len() {
+            return None;
+        }
+
+        Some(Ref::map(data, |data| &data[start..start + len]))
+    }
+
+    fn raw_region_header(data: &[u8]) -> Option<usize> {
+        let mut tlvs = Self::tlv_iter(data)?;
+
+        tlvs.by_ref().for_each(drop);
+
+        tlvs.raw_region_start()
+    }
+
+    // end of the last well formed TLV, or of the raw region behind it. the end of
+    // the base state when only zeros follow it
+    fn extension_region_end(data: &[u8]) -> Option<usize> {
+        let Some(mut tlvs) = Self::tlv_iter(data) else {
+            return zero_after_base::<Self>(data).then_some(Self::len());
+        };
+
+        tlvs.by_ref().for_each(drop);
+
+        match tlvs.raw_region_start() {
+            Some(header) => Some(raw_region_end(data, header).min(data.len())),
+            None => Some(tlvs.cursor()),
+        }
+    }
+
+    // offset of the first TLV with a truncated header or a length running past
+    // the data, the marker offset when the marker itself is missing and the
+    // bytes behind the base state are not all zero
+    fn first_malformed_tlv(data: &[u8]) -> Option<usize> {
+        let Some(mut tlvs) = Self::tlv_iter(data) else {
+            return (data.len() > Self::len() && !zero_after_base::<Self>(data))
+                .then_some(Self::len());
+        };
+
+        tlvs.by_ref().for_each(drop);
+
+        tlvs.is_malformed().then(|| tlvs.cursor())
+    }
+
+    // every type byte stored more than once with all its positions, in order of
+    // first appearance. add_extension rejects duplicates, but outside writers can
+    // still produce them
+    fn find_duplicate_types(data: &[u8]) -> Vec<(u8, Vec<usize>)> {
+        let mut seen: Vec<(u8, Vec<usize>)> = Vec::new();
+
+        for tlv in Self::tlv_iter(data).into_iter().flatten() {
+            match seen
+                .iter_mut()
+                .find(|(ext_type, _)| *ext_type == tlv.ext_type)
+            {
+                Some((_, positions)) => positions.push(tlv.position),
+                None => seen.push((tlv.ext_type, vec![tlv.position])),
+            }
+        }
+
+        seen.retain(|(_, positions)| positions.len() > 1);
+
+        seen
+    }
+
+    // every stored length is within max_len_for_type of its type. a walk that
+    // stops on a malformed TLV is an InvalidAccountData
+    fn validate_lengths(data: &[u8]) -> Result<(), ProgramError> {
+        let Some(mut tlvs) = Self::tlv_iter(data) else {
+            return Ok(());
+        };
+
+        for tlv in tlvs.by_ref() {
+            if Self::max_len_for_type(tlv.ext_type).is_some_and(|max| tlv.len > max) {
+                return Err(Self::to_program_error(
+                    StateExtensionError::ExtensionLengthMismatch,
+                ));
+            }
+        }
+
+        if tlvs.is_malformed() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(())
+    }
+
+    // (type, position) of every TLV whose payload holds needle, each payload is
+    // searched on its own so a match never spans two TLVs
+    fn find_extensions_containing(data: &[u8], needle: &[u8]) -> Vec<(u8, usize)> {
+        if needle.is_empty() {
+            return Vec::new();
+        }
+
+        Self::tlv_iter(data)
+            .into_iter()
+            .flatten()
+            .filter(|tlv| tlv.payload.windows(needle.len()).any(|w| w == needle))
+            .map(|tlv| (tlv.ext_type, tlv.position))
+            .collect()
+    }
+
+    // preallocated rather than exactly sized: unused bytes follow the last TLV
+    fn has_trailing_padding(acc: &AccountInfo) -> bool {
+        Self::free_trailing_bytes(acc) > 0
+    }
+
+    // bytes, headers included, held by free slots and by removable Zerod TLVs,
+    // that removing them would free
+    fn compactable_bytes(acc: &AccountInfo) -> usize {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return 0;
+        }
+
+        let Ok(data) = acc.try_borrow_data() else {
+            return 0;
+        };
+
+        Self::tlv_iter(&data)
+            .map(TlvIter::with_free_slots)
+            .into_iter()
+            .flatten()
+            .filter(|tlv| {
+                tlv.state == EXT_STATE_FREE
+                    || (ExtensionState::from_u8(tlv.state) == Some(ExtensionState::Zerod)
+                        && Self::is_removable(tlv.ext_type))
+            })
+            .map(|tlv| tlv.end() - tlv.position)
+            .sum()
+    }
+
+    // lamports cover rent exemption for the current size, which lamports moved
+    // out of the account behind the program's back can break
+    fn is_rent_exempt_for_extensions(acc: &AccountInfo, rent: &Rent) -> bool {
+        acc.lamports() >= rent.minimum_balance(acc.data_len())
+    }
+
+    // lamports that shrinking the account by compactable_bytes would release
+    fn compactable_rent(acc: &AccountInfo, rent: &AccountInfo) -> u64 {
+        let Ok(rent) = Rent::from_account_info(rent) else {
+            return 0;
+        };
+
+        let data_len = acc.data_len();
+        let bytes = Self::compactable_bytes(acc).min(data_len);
+
+        rent.minimum_balance(data_len) - rent.minimum_balance(data_len - bytes)
+    }
+
+    // bytes after the end of the last well formed TLV and any raw region
+    fn free_trailing_bytes(acc: &AccountInfo) -> usize {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return 0;
+        }
+
+        let Ok(data) = acc.try_borrow_data() else {
+            return 0;
+        };
+
+        match Self::extension_region_end(&data) {
+            Some(end) => data.len() - end,
+            None => 0,
+        }
+    }
+
+    // every payload of E's type starts at an address aligned for E, so the
+    // account can be read zero copy
+    fn check_alignment_for<E: Extension>(data: &[u8]) -> Result<(), ProgramError> {
+        let base = data.as_ptr() as usize;
+
+        for tlv in Self::tlv_iter(data).into_iter().flatten() {
+            if tlv.ext_type == E::ext_type()
+                && !(base + tlv.payload_start()).is_multiple_of(core::mem::align_of::<E>())
+            {
+                return Err(Self::to_program_error(
+                    StateExtensionError::MisalignedExtensionData,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // (type, len) of the TLV with the longest payload, the first one on ties
+    fn largest_extension(data: &[u8]) -> Option<(u8, u16)> {
+        Self::tlv_iter(data)?
+            .map(|tlv| (tlv.ext_type, tlv.len))
+            .reduce(|max, tlv| if tlv.1 > max.1 { tlv } else { max })
+    }
+
+    // (TLV count, bytes from the marker start to the end of the last TLV) in one walk
+    fn extension_stats(data: &[u8]) -> Option<(usize, usize)> {
+        let mut tlvs = Self::tlv_iter(data)?;
+
+        let count = tlvs.by_ref().count();
+
+        Some((count, tlvs.cursor() - Self::len()))
+    }
+
+    // marker through extension_region_end, a raw region included
+    fn extension_region(data: &[u8]) -> Option<&[u8]> {
+        let end = Self::extension_region_end(data)?;
+
+        Some(&data[Self::len()..end])
+    }
+
+    // marker and TLVs as owned bytes, for moving them into another account
+    fn export_extension_region(data: &[u8]) -> Option<Vec<u8>> {
+        Self::extension_region(data).map(<[u8]>::to_vec)
+    }
+
+    /// writes an exported region after the base state of an account without
+    /// extensions, into its zero tail if it was preallocated. a marker already
+    /// behind the base state is a MarkerAlreadyPresent
+    ///
+    /// # Safety
+    ///
+    /// acc's data is reallocated to hold the region, no reference into acc's
+    /// data may be alive across the call and acc's owner must not be reassigned
+    /// while it runs.
+    unsafe fn import_extension_region(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        region: &[u8],
+    ) -> ProgramResult {
+        log!("Import Extension Region : {}", region.len());
+
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        if check_region::<Self>(region)? == 0 {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let data_len = {
+            let data = acc.try_borrow_data().map_err(borrow_failed::<Self>)?;
+
+            if data.len() < Self::len() {
+                return Err(Self::to_program_error(
+                    StateExtensionError::BaseStateNotInitialized,
+                ));
+            }
+
+            if !zero_after_base::<Self>(&data) {
+                return Err(if data[Self::len()..].starts_with(Self::EXT_START_MARKER) {
+                    Self::to_program_error(StateExtensionError::MarkerAlreadyPresent)
+                } else {
+                    ProgramError::AccountAlreadyInitialized
+                });
+            }
+
+            data.len()
+        };
+
+        let shortfall = (Self::len() + region.len()).saturating_sub(data_len);
+
+        if shortfall > 0 {
+            grow_account::<Self>(acc, fee_payer, rent, shortfall)?;
+        }
+
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<Self>)?;
+
+        match data.get_mut(Self::len()..) {
+            Some(data) => unsafe { sol_memcpy(data, region, region.len()) },
+            None => return Err(ProgramError::InvalidAccountData),
+        }
+
+        Ok(())
+    }
+
+    /// swaps old_marker at the end of the base state for EXT_START_MARKER, moving
+    /// the TLVs and resizing the account when the lengths differ. the TLVs are
+    /// padded again for their new addresses, see resize_region
+    ///
+    /// # Safety
+    ///
+    /// Every TLV moves when the marker lengths differ, references from
+    /// get_extension on acc must not be used after the call.
+    unsafe fn migrate_marker(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        old_marker: &[u8],
+    ) -> ProgramResult {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        let marker_start = Self::len();
+
+        {
+            let data = acc.try_borrow_data().map_err(borrow_failed::<Self>)?;
+
+            if data.get(marker_start..marker_start + old_marker.len()) != Some(old_marker) {
+                return Err(ProgramError::InvalidAccountData);
+            }
+        }
+
+        let new_marker = Self::EXT_START_MARKER;
+
+        if new_marker.len() != old_marker.len() {
+            unsafe {
+                resize_region::<Self>(
+                    acc,
+                    fee_payer,
+                    rent,
+                    marker_start,
+                    old_marker.len(),
+                    new_marker.len(),
+                    false,
+                )?
+            };
+        }
+
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<Self>)?;
+
+        unsafe { sol_memcpy(&mut data[marker_start..], new_marker, new_marker.len()) };
+
+        log!(
+            "Migrate Marker : {} -> {}",
+            old_marker.len(),
+            new_marker.len()
+        );
+
+        Ok(())
+    }
+
+    // extension regions of both compare byte for byte, whatever follows them is
+    // ignored as with export_extension_region
+    fn regions_equal(a: &[u8], b: &[u8]) -> bool {
+        match (Self::extension_region(a), Self::extension_region(b)) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// inserts bytes at offset `at` of a base state that was BASE_STATE_LEN -
+    /// bytes.len() long, shifting the rest of the base and the extension region
+    /// right. Self describes the layout after the insert, the constant itself
+    /// cannot change at runtime. padding is kept as stored, an insert that would
+    /// move a padded payload off its alignment is a MisalignedExtensionData
+    ///
+    /// # Safety
+    ///
+    /// The base state and every TLV move right, no reference into acc's data
+    /// may be alive across the call.
+    unsafe fn insert_base_bytes(
+        acc: &AccountInfo,
+        fee_payer: &AccountInfo,
+        rent: &AccountInfo,
+        at: usize,
+        bytes: &[u8],
+    ) -> ProgramResult {
+        if !Self::is_valid_owner(unsafe { acc.owner() }) {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        let old_base_len = Self::len()
+            .checked_sub(bytes.len())
+            .ok_or(ProgramError::InvalidArgument)?;
+
+        if at > old_base_len {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let data_len = {
+            let data = acc.try_borrow_data().map_err(borrow_failed::<Self>)?;
+
+            if data.len() < old_base_len {
+                return Err(Self::to_program_error(
+                    StateExtensionError::BaseStateNotInitialized,
+                ));
+            }
+
+            if data.len() > old_base_len
+                && !Self::check_ext_marker(
+                    data.get(old_base_len..old_base_len + Self::EXT_START_MARKER.len())
+                        .ok_or(ProgramError::InvalidAccountData)?,
+                )
+            {
+                return Err(ProgramError::InvalidAccountData);
+            }
+
+            let ext_data_start = old_base_len + Self::EXT_START_MARKER.len();
+            let tlvs = TlvIter::new(&data, ext_data_start).with_free_slots();
+
+            if !keeps_padding(&data, tlvs, ext_data_start + bytes.len()) {
+                return Err(Self::to_program_error(
+                    StateExtensionError::MisalignedExtensionData,
+                ));
+            }
+
+            data.len()
+        };
+
+        grow_account::<Self>(acc, fee_payer, rent, bytes.len())?;
+
+        log!("Insert Base Bytes : {} at: {}", bytes.len(), at);
+
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<Self>)?;
+        let data_ptr = data.as_mut_ptr();
+
+        unsafe {
+            sol_memmove(
+                data_ptr.add(at + bytes.len()),
+                data_ptr.add(at),
+                data_len - at,
+            );
+            sol_memcpy(&mut data[at..], bytes, bytes.len());
+        }
+
+        Ok(())
+    }
+
+    // sha256 over the domain tag followed by the extension region, bytes past
+    // it such as a preallocated zero tail are not included
+    fn extension_region_hash(data: &[u8], domain: &[u8]) -> Option<[u8; 32]> {
+        let end = Self::extension_region_end(data)?;
+
+        Some(sha256(&[domain, &data[Self::len()..end]]))
+    }
+
+    // converts Token-2022 style entries (u16 type, u16 length, value) into a
+    // marker + TLV region, type_map maps each SPL type to this crate's type byte.
+    // an SPL type of 0 (uninitialized) ends the blob
+    fn from_spl_tlv(blob: &[u8], type_map: &[(u16, u8)]) -> Result<Vec<u8>, ProgramError> {
+        let mut region = Vec::with_capacity(Self::EXT_START_MARKER.len() + blob.len());
+        region.extend_from_slice(Self::EXT_START_MARKER);
+
+        let mut cursor = 0;
+
+        while cursor < blob.len() {
+            let header = blob
+                .get(cursor..cursor + 4)
+                .ok_or(ProgramError::InvalidAccountData)?;
+
+            let spl_type = u16::from_le_bytes([header[0], header[1]]);
+            let len = u16::from_le_bytes([header[2], header[3]]);
+
+            if spl_type == 0 {
+                break;
+            }
+
+            let (_, ext_type) = type_map
+                .iter()
+                .find(|(t, _)| *t == spl_type)
+                .ok_or(ProgramError::InvalidAccountData)?;
+
+            let value = blob
+                .get(cursor + 4..cursor + 4 + len as usize)
+                .ok_or(ProgramError::InvalidAccountData)?;
+
+            region.push(*ext_type);
+            region.push(ExtensionState::Initialized.as_u8());
+            region.extend_from_slice(&len.to_le_bytes());
+            region.extend_from_slice(value);
+
+            cursor += 4 + len as usize;
+        }
+
+        Ok(region)
+    }
+
+    // combines two exported regions into one. TLVs of a come first in their
+    // order, followed by the TLVs of b whose type a does not hold. on_conflict
+    // decides whether a shared type keeps a's or b's entry (in a's slot) or fails.
+    // entries are copied with their padding as is
+    fn merge_regions(
+        a: &[u8],
+        b: &[u8],
+        on_conflict: ConflictPolicy,
+    ) -> Result<Vec<u8>, ProgramError> {
+        let a_tlvs = Self::region_tlvs(a)?;
+        let b_tlvs = Self::region_tlvs(b)?;
+
+        let mut region = Vec::with_capacity(a.len() + b.len());
+        region.extend_from_slice(Self::EXT_START_MARKER);
+
+        for tlv in a_tlvs.iter() {
+            let (src, entry) = match b_tlvs.iter().find(|other| other.ext_type == tlv.ext_type) {
+                Some(other) => match on_conflict {
+                    ConflictPolicy::KeepA => (a, tlv),
+                    ConflictPolicy::KeepB => (b, other),
+                    ConflictPolicy::Error => {
+                        return Err(Self::to_program_error(
+                            StateExtensionError::DuplicateExtension,
+                        ));
+                    }
+                },
+                None => (a, tlv),
+            };
+
+            region.extend_from_slice(&src[entry.position..entry.end()]);
+        }
+
+        for tlv in b_tlvs.iter() {
+            if !a_tlvs.iter().any(|other| other.ext_type == tlv.ext_type) {
+                region.extend_from_slice(&b[tlv.position..tlv.end()]);
+            }
+        }
+
+        Ok(region)
+    }
+
+    // TLVs of a marker + TLV region, which must be well formed to its last byte
+    fn region_tlvs(region: &[u8]) -> Result<Vec<TlvEntry<'_>>, ProgramError> {
+        let marker_len = Self::EXT_START_MARKER.len();
+
+        if !Self::check_ext_marker(
+            region
+                .get(..marker_len)
+                .ok_or(ProgramError::InvalidArgument)?,
+        ) {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let mut tlvs = TlvIter::new(region, marker_len);
+        let entries: Vec<TlvEntry> = tlvs.by_ref().collect();
+
+        if tlvs.is_malformed() || tlvs.cursor() != region.len() {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        Ok(entries)
+    }
+
+    fn detect_format(data: &[u8]) -> FormatVersion {
+        if zero_after_base::<Self>(data) {
+            return FormatVersion::BaseOnly;
+        }
+
+        let Some(mut tlvs) = Self::tlv_iter(data) else {
+            return FormatVersion::Unknown;
+        };
+
+        let mut aligned = false;
+        let mut with_authority = false;
+
+        for tlv in tlvs.by_ref() {
+            if ExtensionState::from_u8(tlv.state).is_none() {
+                return FormatVersion::Unknown;
+            }
+
+            aligned |= tlv.padding != 0;
+            with_authority |= tlv.authority.is_some();
+        }
+
+        if tlvs.is_malformed() {
+            return FormatVersion::Unknown;
+        }
+
+        if with_authority {
+            FormatVersion::WithAuthority
+        } else if aligned {
+            FormatVersion::Aligned
+        } else {
+            FormatVersion::Original
+        }
+    }
+
+    // type -> (position, len) of the first TLV of each type, for indexers doing
+    // many lookups on one account
+    #[cfg(feature = "std")]
+    fn index_map(data: &[u8]) -> std::collections::HashMap<u8, (usize, u16)> {
+        let mut index = std::collections::HashMap::new();
+
+        for tlv in Self::tlv_iter(data).into_iter().flatten() {
+            index.entry(tlv.ext_type).or_insert((tlv.position, tlv.len));
+        }
+
+        index
+    }
+
+    // types added, removed and whose state changed from before to after, types
+    // V does not know are ignored
+    fn diff_extensions<V: ExtensionEnum>(before: &[u8], after: &[u8]) -> ExtensionDiff<V> {
+        let states = |data| -> Vec<(V, Option<ExtensionState>)> {
+            Self::tlv_iter(data)
+                .into_iter()
+                .flatten()
+                .filter_map(|tlv| {
+                    V::from_u8(tlv.ext_type).map(|v| (v, ExtensionState::from_u8(tlv.state)))
+                })
+                .collect()
+        };
+
+        let before = states(before);
+        let after = states(after);
+
+        let mut diff = ExtensionDiff {
+            added: Vec::new(),
+            removed: Vec::new(),
+            state_changed: Vec::new(),
+        };
+
+        for (v, old) in before.iter() {
+            match after.iter().find(|(other, _)| other == v) {
+                None => diff.removed.push(v.clone()),
+                Some((_, new)) => {
+                    if let (Some(old), Some(new)) = (old, new)
+                        && old != new
+                    {
+                        diff.state_changed
+                            .push((v.clone(), old.clone(), new.clone()));
+                    }
+                }
+            }
+        }
+
+        for (v, _) in after.iter() {
+            if !before.iter().any(|(other, _)| other == v) {
+                diff.added.push(v.clone());
+            }
+        }
+
+        diff
+    }
+
+    // first TLV of the given type
+    fn find_tlv(data: &[u8], ext_type: u8) -> Option<TlvEntry<'_>> {
+        Self::tlv_iter(data)?.find(|tlv| tlv.ext_type == ext_type)
+    }
+
+    // stored payload length of the first TLV of the type, to size a buffer for
+    // copy_extension_payload
+    fn extension_payload_len<V: ExtensionEnum>(data: &[u8], ext_type: V) -> Option<u16> {
+        Some(Self::find_tlv(data, ext_type.as_u8())?.len)
+    }
+
+    // every (type, expected_len) of the schema is present with that stored length,
+    // in any order. the first TLV of a type is the one checked, and types outside
+    // the schema are allowed
+    fn conforms_to_schema(data: &[u8], schema: &[(u8, u16)]) -> bool {
+        schema.iter().all(|(ext_type, len)| {
+            Self::find_tlv(data, *ext_type).is_some_and(|tlv| tlv.len == *len)
+        })
+    }
+
+    // payload of the first TLV of the type as an array, None unless it holds exactly N bytes
+    fn get_extension_payload_array<const N: usize>(
+        data: &[u8],
+        ext_type: impl ExtensionEnum,
+    ) -> Option<&[u8; N]> {
+        Self::find_tlv(data, ext_type.as_u8())?
+            .payload
+            .try_into()
+            .ok()
+    }
+
+    // index-th T of the array that follows header_len fixed bytes in the payload
+    fn extension_array_element<E: Extension, T: Pod>(
+        data: &[u8],
+        ext_type: E::ExtensionEnum,
+        header_len: usize,
+        index: usize,
+    ) -> Option<&T> {
+        let tlv = Self::find_tlv(data, ext_type.as_u8())?;
+
+        let size = core::mem::size_of::<T>();
+        let start = index.checked_mul(size)?.checked_add(header_len)?;
+        let element = tlv.payload.get(start..start.checked_add(size)?)?;
+
+        if !(element.as_ptr() as usize).is_multiple_of(core::mem::align_of::<T>()) {
+            return None;
+        }
+
+        Some(unsafe { &*(element.as_ptr() as *const T) })
+    }
+
+    // copies the T at field_offset into the payload out by value, so unlike
+    // extension_array_element it does not care how the field is aligned
+    fn read_field<V: ExtensionEnum, T: Pod>(
+        data: &[u8],
+        ext_type: V,
+        field_offset: usize,
+    ) -> Option<T> {
+        let tlv = Self::find_tlv(data, ext_type.as_u8())?;
+
+        let field = tlv
+            .payload
+            .get(field_offset..field_offset.checked_add(core::mem::size_of::<T>())?)?;
+
+        Some(unsafe { core::ptr::read_unaligned(field.as_ptr() as *const T) })
+    }
+}
+
+// funds and grows the account, then appends the marker (first extension only)
+// and a single TLV after the existing data
+#[allow(clippy::too_many_arguments)]
+unsafe fn append_tlv<S: StateExtension + ?Sized>(
+    acc: &AccountInfo,
+    fee_payer: &AccountInfo,
+    rent: &AccountInfo,
+    ext_type: u8,
+    align: usize,
+    state: ExtensionState,
+    authority: Option<&Pubkey>,
+    payload: &[u8],
+) -> ProgramResult {
+    let position = unsafe {
+        reserve_tlv::<S>(
+            acc,
+            fee_payer,
+            rent,
+            ext_type,
+            align,
+            state,
+            authority,
+            payload.len(),
+        )?
+    };
+
+    let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<S>)?;
+    let start = payload_start(&data, position);
+
+    if let Some(data) = data.get_mut(start..) {
+        unsafe { sol_memcpy(data, payload, payload.len()) };
+    } else {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(())
+}
+
+// grows the account by a TLV of payload_len bytes, or takes a free slot for it,
+// and writes its header (and the marker for the first one), leaving the payload
+// to the caller. Returns the position of the TLV
+#[allow(clippy::too_many_arguments)]
+unsafe fn reserve_tlv<S: StateExtension + ?Sized>(
+    acc: &AccountInfo,
+    fee_payer: &AccountInfo,
+    rent: &AccountInfo,
+    ext_type: u8,
+    align: usize,
+    state: ExtensionState,
+    authority: Option<&Pubkey>,
+    payload_len: usize,
+) -> Result<usize, ProgramError> {
+    if !align.is_power_of_two() || align > MAX_EXT_ALIGN {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if !S::is_valid_owner(unsafe { acc.owner() }) {
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    if !S::ALLOW_REVIVE && acc.lamports() == 0 {
+        return Err(S::to_program_error(StateExtensionError::AccountBeingClosed));
+    }
+
+    if acc.data_is_empty() {
+        return Err(S::to_program_error(
+            StateExtensionError::BaseStateNotInitialized,
+        ));
+    }
+
+    let (data_len, data_addr, raw_region, tlvs_end) = {
+        let data = acc.try_borrow_data().map_err(borrow_failed::<S>)?;
+
+        if data.len() < S::len() {
+            return Err(S::to_program_error(
+                StateExtensionError::BaseStateNotInitialized,
+            ));
+        }
+
+        // a second TLV of a type would hide behind the first, or take its place
+        // once the first moves, so the first one is the only one
+        if S::find_tlv(&data, ext_type).is_some() {
+            return Err(S::to_program_error(StateExtensionError::DuplicateExtension));
+        }
+
+        // every add path comes through here, so this is the one place the limit
+        // is kept. a reused free slot still adds a live TLV
+        if S::tlv_iter(&data).into_iter().flatten().count() >= S::MAX_EXTENSIONS as usize {
+            return Err(S::to_program_error(StateExtensionError::TooManyExtensions));
+        }
+
+        (
+            data.len(),
+            data.as_ptr() as usize,
+            S::raw_region_header(&data),
+            tlvs_end::<S>(&data)?,
+        )
+    };
+
+    if payload_len > u16::MAX as usize {
+        return Err(S::to_program_error(StateExtensionError::ExtensionTooLarge));
+    }
+
+    check_payload_len::<S>(ext_type, payload_len)?;
+
+    let no_extensions = tlvs_end == S::len();
+
+    // TLVs go in front of a raw region, which always stays last, or else into
+    // the zero tail of a preallocated account, which only grows by what is
+    // missing
+    let at = tlvs_end;
+    let spare = if raw_region.is_some() {
+        0
+    } else {
+        data_len - at
+    };
+
+    if S::REUSE_FREE_SLOTS
+        && !no_extensions
+        && let Some(position) =
+            take_free_slot::<S>(acc, ext_type, align, state.clone(), authority, payload_len)?
+    {
+        return Ok(position);
+    }
+
+    // if appending for fist time. a marker left past the end of the data by a
+    // length bug cannot be checked from in here without reading outside the
+    // borrow, remove_tlv wipes the marker before the account shrinks so it
+    // never leaves one behind
+    let marker_len = if no_extensions {
+        S::EXT_START_MARKER.len()
+    } else {
+        0
+    };
+
+    let header_len = match authority {
+        Some(_) => EXT_META_LEN + EXT_AUTHORITY_LEN,
+        None => EXT_META_LEN,
+    };
+
+    let unaligned_payload_addr = data_addr + at + marker_len + header_len;
+    let padding = unaligned_payload_addr.next_multiple_of(align) - unaligned_payload_addr;
+
+    let new_space_to_allocate = marker_len + header_len + padding + payload_len;
+
+    let shortfall = new_space_to_allocate.saturating_sub(spare);
+
+    // an account grows by at most MAX_PERMITTED_DATA_INCREASE in an instruction,
+    // a larger TLV only fits into the zero tail of a preallocated account
+    if shortfall > MAX_PERMITTED_DATA_INCREASE {
+        return Err(S::to_program_error(StateExtensionError::ExtensionTooLarge));
+    }
+
+    let lamports = match shortfall {
+        0 => 0,
+        shortfall => grow_account::<S>(acc, fee_payer, rent, shortfall)?,
+    };
+
+    log!(
+        "Add Extension : {} space: {} lamports: {}",
+        ext_type,
+        new_space_to_allocate,
+        lamports
+    );
+
+    let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<S>)?;
+
+    if raw_region.is_some() {
+        let data_ptr = data.as_mut_ptr();
+
+        unsafe {
+            sol_memmove(
+                data_ptr.add(at + new_space_to_allocate),
+                data_ptr.add(at),
+                data_len - at,
+            )
+        };
+    }
+
+    let Some(region) = data.get_mut(at..at + marker_len + header_len + padding) else {
+        return Err(ProgramError::InvalidAccountData);
+    };
+
+    let (marker, header) = region.split_at_mut(marker_len);
+
+    unsafe { sol_memcpy(marker, S::EXT_START_MARKER, marker_len) };
+
+    write_tlv_header(header, ext_type, state, authority, payload_len);
+
+    Ok(at + marker_len)
+}
+
+// where the next TLV goes: the end of the last one, which is also in front of a
+// raw region or a zero tail, or the end of the base state for a base-only
+// account. a malformed walk, or bytes behind the base state that are neither
+// the marker nor zero, are refused rather than written over
+fn tlvs_end<S: StateExtension + ?Sized>(data: &[u8]) -> Result<usize, ProgramError> {
+    let Some(mut tlvs) = S::tlv_iter(data) else {
+        return match zero_after_base::<S>(data) {
+            true => Ok(S::len()),
+            false => Err(ProgramError::InvalidAccountData),
+        };
+    };
+
+    tlvs.by_ref().for_each(drop);
+
+    if tlvs.is_malformed() {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(tlvs.cursor())
+}
+
+// nothing but zeros behind the base state: a base-only account, exactly sized
+// or preallocated, that every reader and writer treats as holding no extensions
+fn zero_after_base<S: StateExtension + ?Sized>(data: &[u8]) -> bool {
+    data.get(S::len()..)
+        .is_some_and(|tail| tail.iter().all(|byte| *byte == 0))
+}
+
+// the number of TLVs in a marker + TLVs region as export_extension_region
+// returns it, which has to walk cleanly to its end or to the end of a raw
+// region behind the TLVs. it holds the same TLVs add_extension would allow:
+// each type once and no more than MAX_EXTENSIONS
+fn check_region<S: StateExtension + ?Sized>(region: &[u8]) -> Result<usize, ProgramError> {
+    let marker_len = S::EXT_START_MARKER.len();
+
+    if !S::check_ext_marker(
+        region
+            .get(..marker_len)
+            .ok_or(ProgramError::InvalidArgument)?,
+    ) {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut tlvs = TlvIter::new(region, marker_len);
+    let mut seen = [false; 256];
+    let mut count = 0;
+
+    for tlv in tlvs.by_ref() {
+        if core::mem::replace(&mut seen[tlv.ext_type as usize], true) {
+            return Err(S::to_program_error(StateExtensionError::DuplicateExtension));
+        }
+
+        count += 1;
+    }
+
+    if count > S::MAX_EXTENSIONS as usize {
+        return Err(S::to_program_error(StateExtensionError::TooManyExtensions));
+    }
+
+    let end = match tlvs.raw_region_start() {
+        Some(header) => raw_region_end(region, header),
+        None => tlvs.cursor(),
+    };
+
+    if tlvs.is_malformed() || end != region.len() {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(count)
+}
+
+// end of the raw region headed at `header` as its length tells it, which may
+// run past the data
+fn raw_region_end(data: &[u8], header: usize) -> usize {
+    header + EXT_META_LEN + u16::from_le_bytes([data[header + 2], data[header + 3]]) as usize
+}
+
+// an Initialized type 0 TLV without a payload is an all zero header, which the
+// walk takes for a zero tail, so type 0 always carries a payload
+fn check_payload_len<S: StateExtension + ?Sized>(
+    ext_type: u8,
+    payload_len: usize,
+) -> ProgramResult {
+    if ext_type == 0 && payload_len == 0 {
+        return Err(S::to_program_error(
+            StateExtensionError::InvalidExtensionType,
+        ));
+    }
+
+    Ok(())
+}
+
+// header, authority and zeroed padding of a TLV, header holds exactly those
+fn write_tlv_header(
+    header: &mut [u8],
+    ext_type: u8,
+    state: ExtensionState,
+    authority: Option<&Pubkey>,
+    payload_len: usize,
+) {
+    let (header_len, authority_flag) = match authority {
+        Some(_) => (EXT_META_LEN + EXT_AUTHORITY_LEN, EXT_AUTHORITY_FLAG),
+        None => (EXT_META_LEN, 0),
+    };
+    let padding = header.len() - header_len;
+
+    header[0] = ext_type;
+    header[1] = state.as_u8() | ((padding as u8) << EXT_PADDING_SHIFT) | authority_flag;
+    header[2..EXT_META_LEN].copy_from_slice(&(payload_len as u16).to_le_bytes());
+
+    if let Some(authority) = authority {
+        header[EXT_META_LEN..header_len].copy_from_slice(authority);
+    }
+
+    unsafe { sol_memset(&mut header[header_len..], 0, padding) };
+}
+
+// wipes slot and heads it as a free TLV whose payload is the rest of it
+fn write_free_slot(slot: &mut [u8]) {
+    let len = (slot.len() - EXT_META_LEN) as u16;
+
+    unsafe { sol_memset(slot, 0, slot.len()) };
+
+    slot[1] = EXT_STATE_FREE;
+    slot[2..EXT_META_LEN].copy_from_slice(&len.to_le_bytes());
+}
+
+// writes the TLV header into the first free slot it fits, either exactly or
+// leaving room for a smaller free slot behind it. None when no slot fits
+fn take_free_slot<S: StateExtension + ?Sized>(
+    acc: &AccountInfo,
+    ext_type: u8,
+    align: usize,
+    state: ExtensionState,
+    authority: Option<&Pubkey>,
+    payload_len: usize,
+) -> Result<Option<usize>, ProgramError> {
+    let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<S>)?;
+    let data_addr = data.as_ptr() as usize;
+
+    let header_len = match authority {
+        Some(_) => EXT_META_LEN + EXT_AUTHORITY_LEN,
+        None => EXT_META_LEN,
+    };
+
+    let Some(tlvs) = S::tlv_iter(&data) else {
+        return Ok(None);
+    };
+
+    let slot = tlvs
+        .with_free_slots()
+        .filter(|tlv| tlv.state == EXT_STATE_FREE)
+        .find_map(|tlv| {
+            let unaligned_payload_addr = data_addr + tlv.position + header_len;
+            let padding = unaligned_payload_addr.next_multiple_of(align) - unaligned_payload_addr;
+            let needed = header_len + padding + payload_len;
+            let span = tlv.end() - tlv.position;
+
+            (needed == span || needed + EXT_META_LEN <= span).then_some((
+                tlv.position,
+                header_len + padding,
+                needed,
+                span,
+            ))
+        });
+
+    let Some((position, header_len, needed, span)) = slot else {
+        return Ok(None);
+    };
+
+    if needed < span {
+        write_free_slot(&mut data[position + needed..position + span]);
+    }
+
+    write_tlv_header(
+        &mut data[position..position + header_len],
+        ext_type,
+        state,
+        authority,
+        payload_len,
+    );
+
+    log!("Add Extension : {} reused slot: {}", ext_type, position);
+
+    Ok(Some(position))
+}
+
+fn payload_start(data: &[u8], position: usize) -> usize {
+    let padding = (data[position + 1] & EXT_PADDING_MASK) >> EXT_PADDING_SHIFT;
+
+    position + EXT_META_LEN + authority_len(data[position + 1]) + padding as usize
+}
+
+fn authority_len(state_byte: u8) -> usize {
+    if state_byte & EXT_AUTHORITY_FLAG != 0 {
+        EXT_AUTHORITY_LEN
+    } else {
+        0
+    }
+}
+
+// padding the TLV needs once its header sits at offset `at` of data. a padded
+// TLV keeps the alignment its payload has now, up to MAX_EXT_ALIGN, an unpadded
+// one is taken to need none
+fn padding_at(data: &[u8], tlv: &TlvEntry, at: usize) -> usize {
+    let data_addr = data.as_ptr() as usize;
+
+    let align = if tlv.padding == 0 {
+        1
+    } else {
+        (1 << (data_addr + tlv.payload_start()).trailing_zeros()).min(MAX_EXT_ALIGN)
+    };
+
+    let header_len = tlv.payload_start() - tlv.position - tlv.padding as usize;
+    let unaligned_payload_addr = data_addr + at + header_len;
+
+    unaligned_payload_addr.next_multiple_of(align) - unaligned_payload_addr
+}
+
+// true when the TLVs can be written one after another from offset `to` of data
+// with the padding they have stored
+fn keeps_padding<'a>(data: &[u8], tlvs: impl IntoIterator<Item = TlvEntry<'a>>, to: usize) -> bool {
+    let mut at = to;
+
+    tlvs.into_iter().all(|tlv| {
+        let keeps = padding_at(data, &tlv, at) == tlv.padding as usize;
+        at += tlv.end() - tlv.position;
+        keeps
+    })
+}
+
+// the TLVs laid out one after another from offset `to` of data, each padded
+// again for its new address
+fn relayout_tlvs<'a>(
+    data: &[u8],
+    tlvs: impl IntoIterator<Item = TlvEntry<'a>>,
+    to: usize,
+) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    for tlv in tlvs {
+        let header_len = tlv.payload_start() - tlv.position - tlv.padding as usize;
+        let padding = padding_at(data, &tlv, to + out.len());
+
+        let header_start = out.len();
+        out.extend_from_slice(&data[tlv.position..tlv.position + header_len]);
+        out[header_start + 1] =
+            (out[header_start + 1] & !EXT_PADDING_MASK) | ((padding as u8) << EXT_PADDING_SHIFT);
+        out.resize(out.len() + padding, 0);
+        out.extend_from_slice(tlv.payload);
+    }
+
+    out
+}
+
+// a TLV without a stored authority is open to every caller, one with an
+// authority needs that key to have signed
+fn check_authority(data: &[u8], position: usize, authority: Option<&AccountInfo>) -> ProgramResult {
+    if authority_len(data[position + 1]) == 0 {
+        return Ok(());
+    }
+
+    let stored = &data[position + EXT_META_LEN..position + EXT_META_LEN + EXT_AUTHORITY_LEN];
+
+    match authority {
+        Some(authority) if authority.is_signer() && authority.key() == stored => Ok(()),
+        _ => Err(ProgramError::MissingRequiredSignature),
+    }
+}
+
+// rewrites the state bits, keeping the padding bits of the stored byte
+fn set_state_byte(data: &mut [u8], position: usize, state: ExtensionState) {
+    data[position + 1] = (data[position + 1] & !EXT_STATE_MASK) | state.as_u8();
+}
+
+// mutating methods report a data borrow held elsewhere as AccountAlreadyBorrowed
+fn borrow_failed<S: StateExtension + ?Sized>(e: ProgramError) -> ProgramError {
+    match e {
+        ProgramError::AccountBorrowFailed => {
+            S::to_program_error(StateExtensionError::AccountAlreadyBorrowed)
+        }
+        e => e,
+    }
+}
+
+// tops the account up to rent exemption at the grown size and reallocs, returns
+// the lamports moved. lamports already in the account count first, so a
+// prefunded (see prefund_extensions) or overfunded account moves none
+fn grow_account<S: StateExtension + ?Sized>(
+    acc: &AccountInfo,
+    fee_payer: &AccountInfo,
+    rent: &AccountInfo,
+    additional_space: usize,
+) -> Result<u64, ProgramError> {
+    // checked before any lamports move rather than left to realloc
+    if acc.data_len().saturating_add(additional_space) > MAX_PERMITTED_DATA_LENGTH {
+        return Err(S::to_program_error(
+            StateExtensionError::AccountSizeLimitExceeded,
+        ));
+    }
+
+    let rent = Rent::from_account_info(rent)?;
+
+    let lamports = rent
+        .minimum_balance(acc.data_len() + additional_space)
+        .saturating_sub(acc.lamports());
+
+    // transfer lamports for min rent exempt
+    if lamports > 0 {
+        Transfer {
+            from: fee_payer,
+            to: acc,
+            lamports,
+        }
+        .invoke()
+        .map_err(borrow_failed::<S>)?;
+    }
+
+    // realloc acc data and fill it with 0's
+    acc.realloc(acc.data_len() + additional_space, false)
+        .map_err(borrow_failed::<S>)?;
+
+    Ok(lamports)
+}
+
+// replaces the old_len bytes at `at` with new_len bytes (left for the caller to
+// fill), shifting everything after them and resizing the account. Growing is
+// funded by fee_payer, shrinking refunds the freed rent to it. With zeroize the
+// replaced bytes and the vacated tail are wiped before the account shrinks.
+// The TLVs behind the replaced bytes are padded again for their new addresses
+// as in sort_extensions_by, so the size can change by a few bytes more or less
+// than new_len - old_len. Returns the lamports refunded.
+unsafe fn resize_region<S: StateExtension + ?Sized>(
+    acc: &AccountInfo,
+    fee_payer: &AccountInfo,
+    rent: &AccountInfo,
+    at: usize,
+    old_len: usize,
+    new_len: usize,
+    zeroize: bool,
+) -> Result<u64, ProgramError> {
+    if !S::is_valid_owner(unsafe { acc.owner() }) {
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    let data_len = acc.data_len();
+    let tail_start = at + old_len;
+    let new_tail_start = at + new_len;
+
+    if at < S::len() || tail_start > data_len {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let tail_len = data_len - tail_start;
+
+    // a plain move keeps every payload aligned when it is a multiple of what the
+    // payloads need, otherwise the TLVs are laid out again and whatever follows
+    // them (a raw region, a preallocated tail) is copied as it is
+    let relaid = {
+        let data = acc.try_borrow_data().map_err(borrow_failed::<S>)?;
+        let tlvs = TlvIter::new(&data, tail_start).with_free_slots();
+
+        if keeps_padding(&data, tlvs, new_tail_start) {
+            None
+        } else {
+            let mut tlvs = TlvIter::new(&data, tail_start).with_free_slots();
+            let mut tail = relayout_tlvs(&data, tlvs.by_ref(), new_tail_start);
+            tail.extend_from_slice(&data[tlvs.cursor()..]);
+
+            Some(tail)
+        }
+    };
+
+    let new_data_len = new_tail_start + relaid.as_ref().map_or(tail_len, Vec::len);
+
+    if new_data_len > data_len {
+        grow_account::<S>(acc, fee_payer, rent, new_data_len - data_len)?;
+    }
+
+    {
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<S>)?;
+
+        if zeroize {
+            unsafe { sol_memset(&mut data[at..], 0, old_len) };
+        }
+
+        match &relaid {
+            Some(tail) => unsafe { sol_memcpy(&mut data[new_tail_start..], tail, tail.len()) },
+            None => {
+                let data_ptr = data.as_mut_ptr();
+
+                unsafe {
+                    sol_memmove(
+                        data_ptr.add(new_tail_start),
+                        data_ptr.add(tail_start),
+                        tail_len,
+                    )
+                };
+            }
+        }
+
+        if zeroize && new_data_len < data_len {
+            unsafe { sol_memset(&mut data[new_data_len..], 0, data_len - new_data_len) };
+        }
+    }
+
+    if new_data_len >= data_len {
+        return Ok(0);
+    }
+
+    acc.realloc(new_data_len, false)
+        .map_err(borrow_failed::<S>)?;
+
+    let rent = Rent::from_account_info(rent)?;
+
+    let refund = (rent.minimum_balance(data_len) - rent.minimum_balance(new_data_len)).min(
+        acc.lamports()
+            .saturating_sub(rent.minimum_balance(new_data_len)),
+    );
+
+    *acc.try_borrow_mut_lamports()? -= refund;
+    *fee_payer.try_borrow_mut_lamports()? += refund;
+
+    Ok(refund)
+}
+
+fn sha256(vals: &[&[u8]]) -> [u8; 32] {
+    let mut hash = [0; 32];
+
+    #[cfg(target_os = "solana")]
+    unsafe {
+        pinocchio::syscalls::sol_sha256(
+            vals.as_ptr() as *const u8,
+            vals.len() as u64,
+            hash.as_mut_ptr(),
+        );
+    }
+
+    #[cfg(not(target_os = "solana"))]
+    {
+        use sha2::{Digest, Sha256};
+
+        let mut hasher = Sha256::new();
+        vals.iter().for_each(|val| hasher.update(val));
+        hash.copy_from_slice(&hasher.finalize());
+    }
+
+    hash
+}
+
+unsafe fn touch_tlv<S: StateExtension + ?Sized>(
+    acc: &AccountInfo,
+    ext_type: u8,
+    authority: Option<&AccountInfo>,
+) -> ProgramResult {
+    if !S::is_valid_owner(unsafe { acc.owner() }) {
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<S>)?;
+
+    let position = S::find_tlv(&data, ext_type)
+        .ok_or(S::to_program_error(StateExtensionError::ExtensionNotFound))?
+        .position;
+
+    check_authority(&data, position, authority)?;
+
+    let mut tlvs = TlvIter::new(&data, position).with_free_slots();
+    let touched = tlvs.next().ok_or(ProgramError::InvalidAccountData)?;
+    let moved = relayout_tlvs(&data, tlvs.by_ref().chain([touched]), position);
+
+    if moved.len() != tlvs.cursor() - position {
+        return Err(S::to_program_error(
+            StateExtensionError::MisalignedExtensionData,
+        ));
+    }
+
+    unsafe { sol_memcpy(&mut data[position..], &moved, moved.len()) };
+
+    Ok(())
+}
+
+unsafe fn sort_tlvs<S: StateExtension + ?Sized, F: Fn(u8, &[u8]) -> u64>(
+    acc: &AccountInfo,
+    key: F,
+    authority: Option<&AccountInfo>,
+) -> ProgramResult {
+    if !S::is_valid_owner(unsafe { acc.owner() }) {
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<S>)?;
+
+    let Some(tlvs) = S::tlv_iter(&data) else {
+        return Ok(());
+    };
+
+    let mut tlvs = tlvs.with_free_slots();
+    let mut entries: Vec<(bool, u64, usize, TlvEntry)> = tlvs
+        .by_ref()
+        .enumerate()
+        .map(|(index, tlv)| {
+            let free = tlv.state == EXT_STATE_FREE;
+            let key = if free {
+                0
+            } else {
+                key(tlv.ext_type, tlv.payload)
+            };
+
+            (free, key, index, tlv)
+        })
+        .collect();
+
+    if tlvs.is_malformed() {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let region_start = S::len() + S::EXT_START_MARKER.len();
+    let region_end = tlvs.cursor();
+
+    entries.sort_by_key(|(free, key, _, _)| (*free, *key));
+
+    for (index, (_, _, old_index, tlv)) in entries.iter().enumerate() {
+        if index != *old_index {
+            check_authority(&data, tlv.position, authority)?;
+        }
+    }
+
+    let sorted = relayout_tlvs(
+        &data,
+        entries.iter().map(|(_, _, _, tlv)| *tlv),
+        region_start,
+    );
+
+    if sorted.len() != region_end - region_start {
+        return Err(S::to_program_error(
+            StateExtensionError::MisalignedExtensionData,
+        ));
+    }
+
+    drop(entries);
+
+    log!("Sort Extensions : {}", sorted.len());
+
+    unsafe { sol_memcpy(&mut data[region_start..], &sorted, sorted.len()) };
+
+    Ok(())
+}
+
+unsafe fn remove_tlv<S: StateExtension + ?Sized>(
+    acc: &AccountInfo,
+    fee_payer: &AccountInfo,
+    rent: &AccountInfo,
+    ext_type: u8,
+    zeroize: bool,
+    authority: Option<&AccountInfo>,
+) -> ProgramResult {
+    if !S::is_valid_owner(unsafe { acc.owner() }) {
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    let (position, start, end) = {
+        let data = acc.try_borrow_data().map_err(borrow_failed::<S>)?;
+        let mut tlvs = S::tlv_iter(&data)
+            .ok_or(S::to_program_error(StateExtensionError::ExtensionNotFound))?;
+
+        let tlv = tlvs
+            .find(|tlv| tlv.ext_type == ext_type)
+            .ok_or(S::to_program_error(StateExtensionError::ExtensionNotFound))?;
+
+        if !S::is_removable(ext_type) {
+            return Err(S::to_program_error(
+                StateExtensionError::ExtensionNotRemovable,
+            ));
+        }
+
+        check_authority(&data, tlv.position, authority)?;
+
+        let first = tlv.position == S::len() + S::EXT_START_MARKER.len();
+
+        // the marker goes with the last remaining TLV, unless a raw region or
+        // a zero tail still follows it
+        if first
+            && tlvs.next().is_none()
+            && !tlvs.is_malformed()
+            && tlvs.raw_region_start().is_none()
+            && tlvs.cursor() == data.len()
+        {
+            (tlv.position, S::len(), tlv.end())
+        } else {
+            (tlv.position, tlv.position, tlv.end())
+        }
+    };
+
+    // a free slot header has to describe the whole TLV, one too large for that
+    // is removed as usual
+    if S::REUSE_FREE_SLOTS && end - position - EXT_META_LEN <= u16::MAX as usize {
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<S>)?;
+
+        write_free_slot(&mut data[position..end]);
+
+        log!("Free Extension Slot : {} len: {}", ext_type, end - position);
+
+        return Ok(());
+    }
+
+    // a marker left behind in the realloc spare would read as a length bug on
+    // the next add
+    if start == S::len() {
+        let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<S>)?;
+        unsafe { sol_memset(&mut data[start..], 0, S::EXT_START_MARKER.len()) };
+    }
+
+    let refunded =
+        unsafe { resize_region::<S>(acc, fee_payer, rent, start, end - start, 0, zeroize)? };
+
+    log!(
+        "Remove Extension : {} reclaimed: {} refunded: {}",
+        ext_type,
+        end - start,
+        refunded
+    );
+
+    Ok(())
+}
+
+unsafe fn zero_out_tlv<S: StateExtension + ?Sized, E: Extension>(
+    acc: &AccountInfo,
+    ext_type: E::ExtensionEnum,
+    authority: Option<&AccountInfo>,
+) -> ProgramResult {
+    log!("ZeroOut Extension : {}", E::ext_type());
+
+    if !S::is_valid_owner(unsafe { acc.owner() }) {
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    // borrowed before the TLV is looked up, so data already borrowed by the
+    // caller is an AccountAlreadyBorrowed rather than a silent no-op
+    let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<S>)?;
+
+    let Some((position, state)) = S::get_extension_from_acc_data_unchecked::<E>(&data, ext_type)
+        .map(|info| (info.position, info.state))
+    else {
+        return Ok(());
+    };
+
+    if !S::is_removable(E::ext_type()) {
+        return Err(S::to_program_error(
+            StateExtensionError::ExtensionNotRemovable,
+        ));
+    }
+
+    if state != ExtensionState::Initialized {
+        return Err(S::to_program_error(
+            StateExtensionError::ExtensionDataAleadyZerod,
+        ));
+    }
+
+    check_authority(&data, position, authority)?;
+
+    set_state_byte(&mut data, position, ExtensionState::Zerod);
+
+    let ext_data_start = payload_start(&data, position);
+
+    let Some(data) = data.get_mut(ext_data_start..) else {
+        return Err(ProgramError::InvalidAccountData);
+    };
+
+    unsafe { sol_memset(data, 0, E::ext_len() as usize) };
+
+    Ok(())
+}
+
+unsafe fn update_tlv<S: StateExtension + ?Sized, E: Extension>(
+    acc: &AccountInfo,
+    ext_type: E::ExtensionEnum,
+    extension: &E,
+    authority: Option<&AccountInfo>,
+) -> ProgramResult {
+    log!("Mutate Extension : {}", E::ext_type());
+
+    extension.validate()?;
+
+    if !S::is_valid_owner(unsafe { acc.owner() }) {
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    let mut data = acc.try_borrow_mut_data().map_err(borrow_failed::<S>)?;
+
+    let Some((position, state)) = S::get_extension_from_acc_data_unchecked::<E>(&data, ext_type)
+        .map(|info| (info.position, info.state))
+    else {
+        return Ok(());
+    };
+
+    if state == ExtensionState::Zerod {
+        return Err(S::to_program_error(
+            StateExtensionError::ExtensionDataIsNotInitialized,
+        ));
+    }
+
+    check_authority(&data, position, authority)?;
+
+    let ext_data_start = payload_start(&data, position);
+
+    if let Some(data) = data.get_mut(ext_data_start..) {
+        unsafe { sol_memcpy(data, extension.pack(), E::ext_len() as usize) };
+    }
+
+    Ok(())
+}
+
+// (run length, byte) pairs, runs of at most 255
+#[cfg(feature = "compression")]
+fn rle_encode(bytes: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+
+    for run in bytes.chunk_by(|a, b| a == b) {
+        for chunk in run.chunks(u8::MAX as usize) {
+            encoded.push(chunk.len() as u8);
+            encoded.push(chunk[0]);
+        }
+    }
+
+    encoded
+}
+
+// refuses input that decodes past len, so a corrupt prefix cannot inflate it
+#[cfg(feature = "compression")]
+fn rle_decode(encoded: &[u8], len: usize) -> Result<Vec<u8>, ProgramError> {
+    let mut decoded = Vec::with_capacity(len);
+
+    for pair in encoded.chunks(2) {
+        let [count, byte] = pair else {
+            return Err(ProgramError::InvalidAccountData);
+        };
+
+        if decoded.len() + *count as usize > len {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        decoded.resize(decoded.len() + *count as usize, *byte);
+    }
+
+    Ok(decoded)
+}
+
+// pinocchio's memory helpers are no-ops off-chain, these stand in for them so
+// the mutating paths behave the same in host tests and indexers
+#[cfg(not(target_os = "solana"))]
+mod host_memory {
+    pub unsafe fn sol_memcpy(dst: &mut [u8], src: &[u8], n: usize) {
+        unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr(), n) }
+    }
+
+    pub unsafe fn sol_memmove(dst: *mut u8, src: *mut u8, n: usize) {
+        unsafe { core::ptr::copy(src, dst, n) }
+    }
+
+    pub unsafe fn sol_memset(s: &mut [u8], c: u8, n: usize) {
+        unsafe { core::ptr::write_bytes(s.as_mut_ptr(), c, n) }
+    }
+}
+
+// the mutating paths run here against hand built AccountInfos: data edits go
+// through the host_memory fallbacks and the Transfer / CreateAccount CPIs are
+// skipped off-chain, so only the rent refunds show up in lamports
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: Pubkey = [7; 32];
+
+    // runtime account layout: borrow state, signer, writable, padding, key,
+    // owner, lamports, data_len, then the data. the u64 backing keeps the data
+    // 8 byte aligned and `cap` spare bytes leave room to grow
+    struct TestAccount {
+        _buf: Vec<u64>,
+        info: AccountInfo,
+    }
+
+    fn make_acc(key: Pubkey, owner: Pubkey, lamports: u64, data: &[u8], cap: usize) -> TestAccount {
+        let mut buf = vec![0u64; (88 + data.len() + cap).div_ceil(8) + 1];
+        let p = buf.as_mut_ptr() as *mut u8;
+
+        unsafe {
+            *p.add(1) = 1;
+            *p.add(2) = 1;
+            core::ptr::copy_nonoverlapping(key.as_ptr(), p.add(8), 32);
+            core::ptr::copy_nonoverlapping(owner.as_ptr(), p.add(40), 32);
+            *(p.add(72) as *mut u64) = lamports;
+            *(p.add(80) as *mut u64) = data.len() as u64;
+            core::ptr::copy_nonoverlapping(data.as_ptr(), p.add(88), data.len());
+        }
+
+        let info = unsafe { core::mem::transmute::<*mut u8, AccountInfo>(p) };
+
+        TestAccount { _buf: buf, info }
+    }
+
+    fn rent_acc() -> TestAccount {
+        let mut rent = [0u8; 17];
+        rent[..8].copy_from_slice(&3480u64.to_le_bytes());
+        rent[8..16].copy_from_slice(&2.0f64.to_le_bytes());
+        rent[16] = 50;
+
+        make_acc(pinocchio::sysvars::rent::RENT_ID, [0; 32], 1, &rent, 0)
+    }
+
+    fn accounts(base: &[u8]) -> (TestAccount, TestAccount, TestAccount) {
+        (
+            make_acc([1; 32], OWNER, 1_000_000_000, base, 4096),
+            make_acc([2; 32], [0; 32], 1_000_000_000, &[], 0),
+            rent_acc(),
+        )
+    }
+
+    fn data(acc: &TestAccount) -> Vec<u8> {
+        acc.info.try_borrow_data().unwrap().to_vec()
+    }
+
+    // the first len bytes of the account's buffer, which may reach past data_len
+    // into what a shrink left behind
+    fn buffer(acc: &TestAccount, len: usize) -> Vec<u8> {
+        let data = acc.info.try_borrow_data().unwrap();
+
+        unsafe { core::slice::from_raw_parts(data.as_ptr(), len) }.to_vec()
+    }
+
+    fn minimum_balance(rent: &TestAccount, len: usize) -> u64 {
+        Rent::from_account_info(&rent.info)
+            .unwrap()
+            .minimum_balance(len)
+    }
+
+    struct Base;
+
+    impl StateExtension for Base {
+        const BASE_STATE_LEN: usize = 10;
+        const OWNER_PROGRAM: Pubkey = OWNER;
+        const MAX_EXTENSIONS: u8 = 5;
+        const EXT_START_MARKER: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum Kind {
+        A,
+        B,
+        C,
+    }
+
+    impl ExtensionEnum for Kind {
+        fn from_u8(ext_type: u8) -> Option<Self> {
+            match ext_type {
+                0 => Some(Kind::A),
+                1 => Some(Kind::B),
+                2 => Some(Kind::C),
+                _ => None,
+            }
+        }
+
+        fn as_u8(&self) -> u8 {
+            match self {
+                Kind::A => 0,
+                Kind::B => 1,
+                Kind::C => 2,
+            }
+        }
+    }
+
+    #[repr(C)]
+    struct A {
+        v: [u8; 4],
+    }
+
+    #[repr(C)]
+    struct B {
+        v: [u8; 8],
+    }
+
+    #[repr(C)]
+    struct C {
+        v: [u8; 2],
+    }
+
+    impl Extension for A {
+        const LEN: u16 = 4;
+        type ExtensionEnum = Kind;
+        fn ext_type() -> u8 {
+            0
+        }
+    }
+
+    impl Extension for B {
+        const LEN: u16 = 8;
+        type ExtensionEnum = Kind;
+        fn ext_type() -> u8 {
+            1
+        }
+    }
+
+    impl Extension for C {
+        const LEN: u16 = 2;
+        type ExtensionEnum = Kind;
+        fn ext_type() -> u8 {
+            2
+        }
+    }
+
+    // base state, marker, then (type, state byte, payload) TLVs
+    fn account_bytes(tlvs: &[(u8, u8, &[u8])]) -> Vec<u8> {
+        let mut bytes = vec![0u8; Base::BASE_STATE_LEN];
+        bytes.extend_from_slice(Base::EXT_START_MARKER);
+
+        for (ext_type, state, payload) in tlvs {
+            bytes.extend_from_slice(&[*ext_type, *state]);
+            bytes.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(payload);
+        }
+
+        bytes
+    }
+
+    fn region(tlvs: &[(u8, &[u8])]) -> Vec<u8> {
+        let tlvs: Vec<_> = tlvs.iter().map(|(t, p)| (*t, 0, *p)).collect();
+
+        account_bytes(&tlvs)[Base::BASE_STATE_LEN..].to_vec()
+    }
+
+    #[test]
+    fn tlv_iter_walks_in_position_order() {
+        let bytes = account_bytes(&[(1, 0, &[1; 4]), (0, 1, &[2; 2])]);
+        let tlvs: Vec<_> = TlvIter::new(&bytes, 18)
+            .map(|tlv| (tlv.position, tlv.ext_type, tlv.state, tlv.payload))
+            .collect();
+
+        assert_eq!(
+            tlvs,
+            vec![(18, 1, 0, &[1u8; 4][..]), (26, 0, 1, &[2u8; 2][..])]
+        );
+    }
+
+    #[test]
+    fn tlv_iter_skips_free_slots() {
+        let bytes = account_bytes(&[(1, EXT_STATE_FREE, &[0; 4]), (0, 0, &[2; 2])]);
+
+        let types: Vec<_> = TlvIter::new(&bytes, 18).map(|tlv| tlv.ext_type).collect();
+        assert_eq!(types, vec![0]);
+
+        let types: Vec<_> = TlvIter::new(&bytes, 18)
+            .with_free_slots()
+            .map(|tlv| tlv.ext_type)
+            .collect();
+        assert_eq!(types, vec![1, 0]);
+    }
+
+    #[test]
+    fn tlv_iter_stops_on_truncated_tlv() {
+        let mut bytes = account_bytes(&[(1, 0, &[1; 4])]);
+        bytes.extend_from_slice(&[2, 0, 9, 0, 1]);
+
+        let mut tlvs = TlvIter::new(&bytes, 18);
+        assert_eq!(tlvs.by_ref().count(), 1);
+        assert!(tlvs.is_malformed());
+        assert_eq!(tlvs.cursor(), 26);
+
+        let mut tlvs = TlvIter::new(&bytes[..29], 18);
+        assert_eq!(tlvs.by_ref().count(), 1);
+        assert!(tlvs.is_malformed());
+    }
+
+    #[test]
+    fn tlv_iter_stops_at_raw_region() {
+        let bytes = account_bytes(&[(1, 0, &[1; 4]), (0, EXT_STATE_RAW, &[9; 3])]);
+        let mut tlvs = TlvIter::new(&bytes, 18);
+
+        assert_eq!(tlvs.by_ref().count(), 1);
+        assert!(!tlvs.is_malformed());
+        assert_eq!(tlvs.raw_region_start(), Some(26));
+    }
+
+    #[test]
+    fn regions_equal_ignores_base_state() {
+        let a = account_bytes(&[(0, 0, &[1, 1])]);
+        let mut b = a.clone();
+        b[..10].fill(9);
+        assert!(Base::regions_equal(&a, &b));
+
+        let mut c = b.clone();
+        c[22] = 2;
+        assert!(!Base::regions_equal(&a, &c));
+
+        assert!(!Base::regions_equal(&a, &[0u8; 10]));
+
+        let mut bad_marker = a.clone();
+        bad_marker[10] = 0;
+        assert!(!Base::regions_equal(&bad_marker, &bad_marker));
+    }
+
+    #[test]
+    fn regions_equal_ignores_zero_tail() {
+        let a = preallocated(&[(0, 0, &[1; 4]), (1, 0, &[2; 2])], 0);
+        let b = preallocated(&[(0, 0, &[1; 4]), (1, 0, &[2; 2])], 16);
+
+        assert!(Base::regions_equal(&a, &b));
+        assert!(Base::regions_equal(
+            &b,
+            &preallocated(&[(0, 0, &[1; 4]), (1, 0, &[2; 2])], 3)
+        ));
+        assert!(!Base::regions_equal(
+            &b,
+            &preallocated(&[(0, 0, &[1; 4])], 6)
+        ));
+    }
+
+    #[test]
+    fn check_no_overlap_accepts_well_formed_data() {
+        let bytes = account_bytes(&[(0, 0, &[1; 4]), (1, 0, &[2; 8])]);
+        assert!(Base::check_no_overlap(&bytes).is_ok());
+
+        let mut truncated = bytes.clone();
+        truncated.extend_from_slice(&[2, 0, 9, 0]);
+        assert_eq!(
+            Base::check_no_overlap(&truncated),
+            Err(StateExtensionError::OverlappingExtensions.into())
+        );
+
+        // A's length of 6 runs two bytes into B's header
+        let mut overlapping = bytes.clone();
+        overlapping[20] = 6;
+        assert_eq!(
+            Base::check_no_overlap(&overlapping),
+            Err(StateExtensionError::OverlappingExtensions.into())
+        );
+        assert!(!Base::is_extensible(
+            &make_acc([1; 32], OWNER, 1, &overlapping, 0).info
+        ));
+
+        let mut unmarked = vec![0u8; 10];
+        unmarked.extend_from_slice(&[9; 4]);
+        assert_eq!(
+            Base::check_no_overlap(&unmarked),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn extension_region_hash_skips_zero_tail() {
+        let exact = preallocated(&[(0, 0, &[1; 4])], 0);
+        let padded = preallocated(&[(0, 0, &[1; 4])], 16);
+        let hash = Base::extension_region_hash(&exact, b"test");
+
+        assert!(hash.is_some());
+        assert_eq!(Base::extension_region_hash(&padded, b"test"), hash);
+        assert_ne!(Base::extension_region_hash(&padded, b"other"), hash);
+        assert_ne!(
+            Base::extension_region_hash(&preallocated(&[(0, 0, &[2; 4])], 16), b"test"),
+            hash
+        );
+    }
+
+    #[test]
+    fn merge_regions_applies_conflict_policy() {
+        let a = region(&[(1, &[1; 4]), (2, &[2; 8])]);
+        let b = region(&[(2, &[9; 8]), (3, &[3; 2])]);
+
+        assert_eq!(
+            Base::merge_regions(&a, &b, ConflictPolicy::KeepA).unwrap(),
+            region(&[(1, &[1; 4]), (2, &[2; 8]), (3, &[3; 2])])
+        );
+        assert_eq!(
+            Base::merge_regions(&a, &b, ConflictPolicy::KeepB).unwrap(),
+            region(&[(1, &[1; 4]), (2, &[9; 8]), (3, &[3; 2])])
+        );
+        assert!(Base::merge_regions(&a, &b, ConflictPolicy::Error).is_err());
+
+        let mut truncated = region(&[(3, &[3; 2])]);
+        truncated.push(1);
+        assert!(Base::merge_regions(&a, &truncated, ConflictPolicy::KeepA).is_err());
+    }
+
+    #[test]
+    fn from_spl_tlv_maps_types() {
+        let blob = [3u8, 0, 2, 0, 9, 9, 7, 0, 1, 0, 5, 0, 0, 0, 0];
+        let region = Base::from_spl_tlv(&blob, &[(3, 1), (7, 2)]).unwrap();
+
+        let mut bytes = vec![0u8; 10];
+        bytes.extend_from_slice(&region);
+        assert_eq!(
+            Base::get_extension_variants_from_acc_data_uncheked::<Kind>(&bytes),
+            Some(vec![Kind::B, Kind::C])
+        );
+
+        assert!(Base::from_spl_tlv(&blob, &[(3, 1)]).is_err());
+    }
+
+    #[test]
+    fn parse_snapshot_account_reads_tlvs() {
+        let mut bytes = vec![0xaa; 26];
+        assert!(
+            parse_snapshot_account(&bytes, 26, &[1, 2])
+                .unwrap()
+                .is_empty()
+        );
+
+        bytes.extend_from_slice(&[1, 2, 5, 0, 2, 0, 7, 7, 6, 1, 1, 0, 9]);
+        assert_eq!(
+            parse_snapshot_account(&bytes, 26, &[1, 2]).unwrap(),
+            vec![
+                (5, ExtensionState::Initialized, vec![7, 7]),
+                (6, ExtensionState::Zerod, vec![9]),
+            ]
+        );
+        assert!(parse_snapshot_account(&bytes, 26, &[1, 3]).is_err());
+        assert!(parse_snapshot_account(&bytes, 40, &[1, 2]).is_err());
+
+        bytes.push(1);
+        assert!(parse_snapshot_account(&bytes, 26, &[1, 2]).is_err());
+    }
+
+    #[test]
+    fn find_duplicate_types_reports_positions() {
+        let mut bytes = account_bytes(&[(0, 0, &[1; 4]), (1, 0, &[9; 2])]);
+        assert!(Base::find_duplicate_types(&bytes).is_empty());
+
+        bytes.extend_from_slice(&[0, 0, 4, 0, 2, 2, 2, 2]);
+        assert_eq!(Base::find_duplicate_types(&bytes), vec![(0, vec![18, 32])]);
+    }
+
+    #[test]
+    fn validate_lengths_checks_type_bounds() {
+        struct Bounded;
+
+        impl StateExtension for Bounded {
+            const BASE_STATE_LEN: usize = 10;
+            const OWNER_PROGRAM: Pubkey = OWNER;
+            const MAX_EXTENSIONS: u8 = 4;
+            const EXT_START_MARKER: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+
+            fn max_len_for_type(ext_type: u8) -> Option<u16> {
+                (ext_type == 0).then_some(4)
+            }
+        }
+
+        let mut bytes = account_bytes(&[(0, 0, &[1; 4]), (1, 0, &[9; 9])]);
+        assert!(Bounded::validate_lengths(&bytes).is_ok());
+
+        bytes.extend_from_slice(&[0, 0, 5, 0, 1, 1, 1, 1, 1]);
+        assert_eq!(
+            Bounded::validate_lengths(&bytes),
+            Err(StateExtensionError::ExtensionLengthMismatch.into())
+        );
+
+        assert!(Bounded::validate_lengths(&[0u8; 10]).is_ok());
+    }
+
+    #[test]
+    fn add_update_remove_extensions() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1, 2, 3, 4] })
+                .unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &B { v: [5; 8] }).unwrap();
+            assert_eq!(
+                data(&acc)[10..],
+                account_bytes(&[(0, 0, &[1, 2, 3, 4]), (1, 0, &[5; 8])])[10..]
+            );
+
+            Base::update_extension(&acc.info, Kind::A, &A { v: [9; 4] }).unwrap();
+            assert_eq!(
+                Base::get_extension::<A>(&acc.info, Kind::A).unwrap().ext.v,
+                [9; 4]
+            );
+
+            Base::remove_extension_zeroize::<A>(&acc.info, &payer.info, &rent.info, Kind::A)
+                .unwrap();
+            assert_eq!(data(&acc).len(), 10 + 8 + 12);
+            assert_eq!(
+                Base::get_extension::<B>(&acc.info, Kind::B).unwrap().ext.v,
+                [5; 8]
+            );
+            // the 8 bytes B moved out of were wiped before the account shrank
+            assert_eq!(buffer(&acc, 38)[30..], [0; 8]);
+
+            Base::remove_extension::<B>(&acc.info, &payer.info, &rent.info, Kind::B).unwrap();
+            assert_eq!(data(&acc), [3; 10]);
+            assert!(
+                Base::remove_extension::<B>(&acc.info, &payer.info, &rent.info, Kind::B).is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn only_the_zeroizing_remove_wipes_the_vacated_bytes() {
+        for zeroize in [false, true] {
+            let (acc, payer, rent) = accounts(&[3; 10]);
+
+            unsafe {
+                Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+                Base::add_extension(&acc.info, &payer.info, &rent.info, &B { v: [5; 8] }).unwrap();
+
+                if zeroize {
+                    Base::remove_extension_zeroize::<A>(&acc.info, &payer.info, &rent.info, Kind::A)
+                } else {
+                    Base::remove_extension::<A>(&acc.info, &payer.info, &rent.info, Kind::A)
+                }
+                .unwrap();
+            }
+
+            assert_eq!(data(&acc)[10..], account_bytes(&[(1, 0, &[5; 8])])[10..]);
+            assert_eq!(
+                buffer(&acc, 38)[30..],
+                if zeroize { [0; 8] } else { [5; 8] }
+            );
+        }
+    }
+
+    #[test]
+    fn variants_follow_adds_and_removes() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &B { v: [2; 8] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [3; 2] }).unwrap();
+            assert_eq!(
+                Base::get_extension_variants(&acc.info),
+                Some(vec![Kind::A, Kind::B, Kind::C])
+            );
+
+            Base::remove_extension::<B>(&acc.info, &payer.info, &rent.info, Kind::B).unwrap();
+            assert_eq!(
+                Base::get_extension_variants(&acc.info),
+                Some(vec![Kind::A, Kind::C])
+            );
+        }
+    }
+
+    #[test]
+    fn writes_under_a_held_borrow_fail() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+        let borrowed = Err(StateExtensionError::AccountAlreadyBorrowed.into());
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [1, 2] }).unwrap();
+
+            let held = acc.info.try_borrow_mut_data().unwrap();
+            assert_eq!(
+                Base::update_extension(&acc.info, Kind::C, &C { v: [9; 2] }),
+                borrowed
+            );
+            assert_eq!(
+                Base::zero_out_extension_data::<C>(&acc.info, Kind::C),
+                borrowed
+            );
+            assert_eq!(
+                Base::reinitialize_extension(&acc.info, Kind::C, &C { v: [9; 2] }),
+                borrowed
+            );
+            drop(held);
+
+            assert_eq!(data(&acc)[22..], [1, 2]);
+        }
+    }
+
+    #[test]
+    fn errors_carry_the_code_offset() {
+        struct Offset;
+
+        impl StateExtension for Offset {
+            const BASE_STATE_LEN: usize = 10;
+            const OWNER_PROGRAM: Pubkey = OWNER;
+            const MAX_EXTENSIONS: u8 = 5;
+            const EXT_START_MARKER: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+            const ERROR_CODE_OFFSET: u32 = 1000;
+        }
+
+        struct Wrapping;
+
+        impl StateExtension for Wrapping {
+            const BASE_STATE_LEN: usize = 10;
+            const OWNER_PROGRAM: Pubkey = OWNER;
+            const MAX_EXTENSIONS: u8 = 5;
+            const EXT_START_MARKER: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+            const ERROR_CODE_OFFSET: u32 = u32::MAX;
+        }
+
+        let acc = make_acc([1; 32], OWNER, 0, &[3; 10], 64);
+        let (_, payer, rent) = accounts(&[]);
+
+        assert_eq!(
+            unsafe { Offset::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }) },
+            Err(ProgramError::Custom(1002))
+        );
+        assert_eq!(
+            unsafe {
+                Wrapping::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] })
+            },
+            Err(ProgramError::Custom(1))
+        );
+
+        let _held = acc.info.try_borrow_data().unwrap();
+        assert_eq!(
+            unsafe { Offset::update_extension(&acc.info, Kind::A, &A { v: [1; 4] }) },
+            Err(ProgramError::Custom(1014))
+        );
+    }
+
+    #[repr(C)]
+    struct Aligned {
+        v: u64,
+    }
+
+    impl Extension for Aligned {
+        const LEN: u16 = 8;
+        const ALIGN: usize = 8;
+        type ExtensionEnum = Kind;
+        fn ext_type() -> u8 {
+            1
+        }
+    }
+
+    #[test]
+    fn shifted_tlvs_stay_aligned() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [1, 2] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &Aligned { v: 77 }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [4; 4] }).unwrap();
+
+            Base::remove_extension::<C>(&acc.info, &payer.info, &rent.info, Kind::C).unwrap();
+            assert!(Base::check_alignment_for::<Aligned>(&data(&acc)).is_ok());
+            assert_eq!(
+                Base::get_extension::<Aligned>(&acc.info, Kind::B)
+                    .unwrap()
+                    .ext
+                    .v,
+                77
+            );
+            assert_eq!(
+                Base::get_extension::<A>(&acc.info, Kind::A).unwrap().ext.v,
+                [4; 4]
+            );
+
+            Base::touch_extension::<A>(&acc.info, Kind::A).unwrap();
+            Base::touch_extension::<Aligned>(&acc.info, Kind::B).unwrap();
+            assert!(Base::check_alignment_for::<Aligned>(&data(&acc)).is_ok());
+            assert_eq!(
+                Base::get_extension_variants(&acc.info),
+                Some(vec![Kind::A, Kind::B])
+            );
+            assert_eq!(
+                Base::get_extension::<Aligned>(&acc.info, Kind::B)
+                    .unwrap()
+                    .ext
+                    .v,
+                77
+            );
+        }
+    }
+
+    #[test]
+    fn unpack_rejects_misaligned_payload() {
+        let bytes = [0u64; 3];
+        let bytes = unsafe { core::slice::from_raw_parts((bytes.as_ptr() as *const u8).add(1), 8) };
+
+        assert_eq!(
+            unsafe { Aligned::unpack(bytes) }.err(),
+            Some(StateExtensionError::MisalignedExtensionData.into())
+        );
+    }
+
+    fn preallocated(tlvs: &[(u8, u8, &[u8])], tail: usize) -> Vec<u8> {
+        let mut bytes = account_bytes(tlvs);
+        bytes.resize(bytes.len() + tail, 0);
+        bytes
+    }
+
+    #[test]
+    fn zero_tail_ends_the_walk() {
+        let bytes = preallocated(&[(0, 0, &[1; 4])], 16);
+        let acc = make_acc([1; 32], OWNER, 1_000_000_000, &bytes, 0);
+
+        assert_eq!(Base::free_trailing_bytes(&acc.info), 16);
+        assert_eq!(Base::extension_stats(&bytes), Some((1, 16)));
+        assert_eq!(Base::get_extension_variants(&acc.info), Some(vec![Kind::A]));
+
+        let mut tlvs = TlvIter::new(&bytes, 18);
+        assert_eq!(tlvs.by_ref().count(), 1);
+        assert!(!tlvs.is_malformed());
+    }
+
+    #[test]
+    fn has_trailing_padding_sees_the_zero_tail() {
+        let exact = make_acc([1; 32], OWNER, 1, &account_bytes(&[(0, 0, &[1; 4])]), 0);
+        assert!(!Base::has_trailing_padding(&exact.info));
+
+        let padded = make_acc([1; 32], OWNER, 1, &preallocated(&[(0, 0, &[1; 4])], 16), 0);
+        assert!(Base::has_trailing_padding(&padded.info));
+
+        let zeroed_payload = account_bytes(&[(1, 0, &[0; 4])]);
+        let zeroed_payload = make_acc([1; 32], OWNER, 1, &zeroed_payload, 0);
+        assert!(!Base::has_trailing_padding(&zeroed_payload.info));
+    }
+
+    #[test]
+    fn adds_fill_the_zero_tail_first() {
+        let (acc, payer, rent) = accounts(&preallocated(&[(0, 0, &[1; 4])], 16));
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &B { v: [2; 8] }).unwrap();
+            assert_eq!(data(&acc).len(), 10 + 8 + 8 + 16);
+            assert_eq!(Base::free_trailing_bytes(&acc.info), 4);
+
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [3; 2] }).unwrap();
+            assert_eq!(Base::free_trailing_bytes(&acc.info), 0);
+            assert_eq!(
+                data(&acc)[10..],
+                account_bytes(&[(0, 0, &[1; 4]), (1, 0, &[2; 8]), (2, 0, &[3; 2])])[10..]
+            );
+        }
+    }
+
+    #[test]
+    fn removing_the_last_tlv_keeps_the_marker_before_a_zero_tail() {
+        let (acc, payer, rent) = accounts(&preallocated(&[(0, 0, &[1; 4])], 16));
+
+        unsafe {
+            Base::remove_extension::<A>(&acc.info, &payer.info, &rent.info, Kind::A).unwrap();
+            assert_eq!(data(&acc), preallocated(&[], 16));
+            assert_eq!(
+                Base::get_extension_variants::<Kind>(&acc.info),
+                Some(vec![])
+            );
+
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [3; 2] }).unwrap();
+            assert_eq!(data(&acc), preallocated(&[(2, 0, &[3; 2])], 10));
+        }
+    }
+
+    #[test]
+    fn first_add_claims_a_zeroed_base_only_tail() {
+        let mut bytes = vec![3u8; 10];
+        bytes.resize(30, 0);
+        let (acc, payer, rent) = accounts(&bytes);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+        }
+
+        assert_eq!(data(&acc)[10..], preallocated(&[(0, 0, &[1; 4])], 4)[10..]);
+
+        bytes[20] = 1;
+        let (acc, payer, rent) = accounts(&bytes);
+
+        assert_eq!(
+            unsafe { Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }) },
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn create_with_extensions_takes_an_exported_region() {
+        let source = account_bytes(&[(0, 0, &[1; 4]), (2, 0, &[7; 2])]);
+        let region = Base::export_extension_region(&source).unwrap();
+        let (_, payer, rent) = accounts(&[]);
+
+        // host CPIs are no-ops, so the account is handed in at its final size
+        let acc = make_acc([1; 32], OWNER, 0, &vec![0; source.len()], 0);
+
+        unsafe {
+            Base::create_with_extensions(&payer.info, &acc.info, &rent.info, &[3; 10], &region)
+                .unwrap();
+        }
+
+        assert_eq!(data(&acc)[..10], [3; 10]);
+        assert_eq!(data(&acc)[10..], source[10..]);
+
+        let truncated = &region[..region.len() - 1];
+        let unmarked = &region[Base::EXT_START_MARKER.len()..];
+
+        for region in [truncated, unmarked] {
+            assert_eq!(
+                unsafe {
+                    Base::create_with_extensions(
+                        &payer.info,
+                        &acc.info,
+                        &rent.info,
+                        &[3; 10],
+                        region,
+                    )
+                },
+                Err(ProgramError::InvalidArgument)
+            );
+        }
+
+        let base_only = make_acc([3; 32], OWNER, 0, &[0; 10], 0);
+
+        unsafe {
+            Base::create_with_extensions(&payer.info, &base_only.info, &rent.info, &[3; 10], &[])
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn import_refuses_an_account_holding_a_marker() {
+        let imported = region(&[(0, &[1; 4])]);
+
+        for bytes in [preallocated(&[], 16), account_bytes(&[(1, 0, &[2; 8])])] {
+            let (acc, payer, rent) = accounts(&bytes);
+
+            assert_eq!(
+                unsafe {
+                    Base::import_extension_region(&acc.info, &payer.info, &rent.info, &imported)
+                },
+                Err(StateExtensionError::MarkerAlreadyPresent.into())
+            );
+            assert_eq!(data(&acc), bytes);
+        }
+
+        // a doubled marker in the region itself does not walk
+        let (acc, payer, rent) = accounts(&[3; 10]);
+        let mut doubled = Base::EXT_START_MARKER.to_vec();
+        doubled.extend_from_slice(&imported);
+
+        assert_eq!(
+            unsafe { Base::import_extension_region(&acc.info, &payer.info, &rent.info, &doubled) },
+            Err(ProgramError::InvalidArgument)
+        );
+
+        // a zero tail is filled rather than grown past
+        let mut bytes = vec![3u8; 10];
+        bytes.resize(30, 0);
+        let (acc, payer, rent) = accounts(&bytes);
+
+        unsafe {
+            Base::import_extension_region(&acc.info, &payer.info, &rent.info, &imported).unwrap();
+        }
+        assert_eq!(data(&acc)[10..], preallocated(&[(0, 0, &[1; 4])], 4)[10..]);
+    }
+
+    // exports the region of an account built through add_extension and imports it
+    // into a fresh one, `types` picks which of A, B and C are added in that order
+    fn import_round_trip<S: StateExtension>(types: &[u8]) {
+        let (source, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            for ext_type in types {
+                match ext_type {
+                    0 => S::add_extension(&source.info, &payer.info, &rent.info, &A { v: [1; 4] }),
+                    1 => S::add_extension(&source.info, &payer.info, &rent.info, &B { v: [2; 8] }),
+                    _ => S::add_extension(&source.info, &payer.info, &rent.info, &C { v: [3; 2] }),
+                }
+                .unwrap();
+            }
+        }
+
+        let region = S::export_extension_region(&data(&source)).unwrap();
+        let (target, _, _) = accounts(&[3; 10]);
+
+        unsafe {
+            S::import_extension_region(&target.info, &payer.info, &rent.info, &region).unwrap();
+        }
+        assert_eq!(data(&target), data(&source));
+        assert_eq!(
+            S::try_get_extension_variants::<Kind>(&target.info)
+                .unwrap()
+                .iter()
+                .map(Kind::as_u8)
+                .collect::<Vec<_>>(),
+            types
+        );
+
+        let created = make_acc([4; 32], OWNER, 0, &vec![0; data(&source).len()], 0);
+
+        unsafe {
+            S::create_with_extensions(&payer.info, &created.info, &rent.info, &[3; 10], &region)
+                .unwrap();
+        }
+        assert_eq!(data(&created), data(&source));
+    }
+
+    #[test]
+    fn import_round_trips_with_short_and_empty_markers() {
+        struct OneByte;
+
+        impl StateExtension for OneByte {
+            const BASE_STATE_LEN: usize = 10;
+            const OWNER_PROGRAM: Pubkey = OWNER;
+            const MAX_EXTENSIONS: u8 = 5;
+            const EXT_START_MARKER: &[u8] = &[1];
+        }
+
+        struct Unmarked;
+
+        impl StateExtension for Unmarked {
+            const BASE_STATE_LEN: usize = 10;
+            const OWNER_PROGRAM: Pubkey = OWNER;
+            const MAX_EXTENSIONS: u8 = 5;
+            const EXT_START_MARKER: &[u8] = &[];
+        }
+
+        // the first TLV of type 1 starts with the same byte as the marker
+        import_round_trip::<OneByte>(&[1, 0, 2]);
+        import_round_trip::<Unmarked>(&[1, 0, 2]);
+        import_round_trip::<Base>(&[1, 0, 2]);
+    }
+
+    #[test]
+    fn type_zero_flags_are_refused() {
+        struct Flag;
+
+        impl Extension for Flag {
+            const LEN: u16 = 0;
+            type ExtensionEnum = Kind;
+            fn ext_type() -> u8 {
+                0
+            }
+        }
+
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        assert_eq!(
+            unsafe { Base::add_extension(&acc.info, &payer.info, &rent.info, &Flag) },
+            Err(StateExtensionError::InvalidExtensionType.into())
+        );
+    }
+
+    #[test]
+    fn compactable_bytes_counts_free_slots() {
+        struct Reusing;
+
+        impl StateExtension for Reusing {
+            const BASE_STATE_LEN: usize = 10;
+            const OWNER_PROGRAM: Pubkey = OWNER;
+            const MAX_EXTENSIONS: u8 = 5;
+            const EXT_START_MARKER: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+            const REUSE_FREE_SLOTS: bool = true;
+        }
+
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Reusing::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Reusing::add_extension(&acc.info, &payer.info, &rent.info, &B { v: [2; 8] }).unwrap();
+            Reusing::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [3; 2] }).unwrap();
+            assert_eq!(Reusing::compactable_bytes(&acc.info), 0);
+
+            Reusing::remove_extension::<B>(&acc.info, &payer.info, &rent.info, Kind::B).unwrap();
+            assert_eq!(Reusing::compactable_bytes(&acc.info), 12);
+
+            Reusing::zero_out_extension_data::<C>(&acc.info, Kind::C).unwrap();
+            assert_eq!(Reusing::compactable_bytes(&acc.info), 18);
+
+            // a later add takes the free slot, ahead of C on disk, without growing
+            assert_eq!(data(&acc).len(), 10 + 8 + 8 + 12 + 6);
+            Reusing::add_extension(&acc.info, &payer.info, &rent.info, &B { v: [4; 8] }).unwrap();
+            assert_eq!(data(&acc).len(), 10 + 8 + 8 + 12 + 6);
+            assert_eq!(
+                Reusing::get_extension::<B>(&acc.info, Kind::B)
+                    .map(|info| (info.ext.v, info.position)),
+                Some(([4; 8], 26))
+            );
+            assert_eq!(Reusing::compactable_bytes(&acc.info), 6);
+            assert_eq!(
+                Reusing::get_extension_variants(&acc.info),
+                Some(vec![Kind::A, Kind::B, Kind::C])
+            );
+        }
+    }
+
+    #[test]
+    fn expected_size_for_plain_tlvs() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+        assert!(Base::data_len_matches(&acc.info, &[]));
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [2; 2] }).unwrap();
+        }
+        assert_eq!(Base::expected_size_for(&[4, 2]), 10 + 8 + 8 + 6);
+        assert!(Base::data_len_matches(&acc.info, &[4, 2]));
+        assert!(!Base::data_len_matches(&acc.info, &[4]));
+    }
+
+    #[test]
+    fn zero_and_reinitialize_extension() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [1, 2] }).unwrap();
+            Base::zero_out_extension_data::<C>(&acc.info, Kind::C).unwrap();
+
+            let info = Base::get_extension::<C>(&acc.info, Kind::C).unwrap();
+            assert_eq!((info.ext.v, info.state), ([0, 0], ExtensionState::Zerod));
+
+            Base::reinitialize_extension(&acc.info, Kind::C, &C { v: [7, 7] }).unwrap();
+
+            let info = Base::get_extension::<C>(&acc.info, Kind::C).unwrap();
+            assert_eq!(
+                (info.ext.v, info.state),
+                ([7, 7], ExtensionState::Initialized)
+            );
+        }
+    }
+
+    #[test]
+    fn extensions_read_again_after_a_realloc() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [1, 2] }).unwrap();
+
+            let c = Base::get_extension::<C>(&acc.info, Kind::C).unwrap();
+            let strict = Base::get_extension_strict::<C>(&acc.info, Kind::C)
+                .unwrap()
+                .unwrap();
+            assert_eq!((c.ext.v, strict.ext.v, c.position), ([1, 2], [1, 2], 18));
+
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [3; 4] }).unwrap();
+            Base::remove_extension::<C>(&acc.info, &payer.info, &rent.info, Kind::C).unwrap();
+
+            // A moved into the position C was read from, the old references are
+            // stale and a new read is what sees the account as it is now
+            assert!(Base::get_extension::<C>(&acc.info, Kind::C).is_none());
+            assert!(matches!(
+                Base::get_extension_strict::<C>(&acc.info, Kind::C),
+                Ok(None)
+            ));
+
+            let a = Base::get_extension::<A>(&acc.info, Kind::A).unwrap();
+            assert_eq!((a.ext.v, a.position), ([3; 4], 18));
+            assert_eq!(
+                Base::get_extension_strict::<A>(&acc.info, Kind::A)
+                    .unwrap()
+                    .map(|info| info.ext.v),
+                Some([3; 4])
+            );
+            assert_eq!(data(&acc).len(), 10 + 8 + 8);
+        }
+    }
+
+    #[test]
+    fn reinitialize_fills_a_reserved_extension_and_refuses_a_missing_one() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            assert_eq!(
+                Base::reinitialize_extension(&acc.info, Kind::A, &A { v: [1; 4] }),
+                Err(StateExtensionError::ExtensionNotFound.into())
+            );
+
+            Base::reserve_extension::<A>(&acc.info, &payer.info, &rent.info).unwrap();
+
+            let info = Base::get_extension::<A>(&acc.info, Kind::A).unwrap();
+            assert_eq!((info.ext.v, info.state), ([0; 4], ExtensionState::Zerod));
+
+            assert_eq!(
+                Base::reinitialize_extension(&acc.info, Kind::B, &B { v: [2; 8] }),
+                Err(StateExtensionError::ExtensionNotFound.into())
+            );
+            Base::reinitialize_extension(&acc.info, Kind::A, &A { v: [1; 4] }).unwrap();
+
+            let info = Base::get_extension::<A>(&acc.info, Kind::A).unwrap();
+            assert_eq!(
+                (info.ext.v, info.state),
+                ([1; 4], ExtensionState::Initialized)
+            );
+            assert_eq!(
+                Base::reinitialize_extension(&acc.info, Kind::A, &A { v: [3; 4] }),
+                Err(StateExtensionError::ExtensionDataAlreadyInitialized.into())
+            );
+        }
+    }
+
+    #[test]
+    fn a_second_tlv_of_a_type_is_refused() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+        let owner = make_acc([9; 32], [0; 32], 0, &[], 0);
+
+        unsafe {
+            Base::add_extension_with_authority(
+                &acc.info,
+                &payer.info,
+                &rent.info,
+                &A { v: [1; 4] },
+                &[9; 32],
+            )
+            .unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [2; 2] }).unwrap();
+
+            // an unprotected copy behind the protected TLV would take over once
+            // the protected one moved behind it
+            assert_eq!(
+                Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [6; 4] }),
+                Err(StateExtensionError::DuplicateExtension.into())
+            );
+            assert_eq!(
+                Base::reserve_extension::<C>(&acc.info, &payer.info, &rent.info),
+                Err(StateExtensionError::DuplicateExtension.into())
+            );
+            assert_eq!(
+                Base::update_extension(&acc.info, Kind::A, &A { v: [6; 4] }),
+                Err(ProgramError::MissingRequiredSignature)
+            );
+            Base::update_extension_authorized(&acc.info, &owner.info, Kind::A, &A { v: [5; 4] })
+                .unwrap();
+
+            assert_eq!(
+                Base::get_extension::<A>(&acc.info, Kind::A).unwrap().ext.v,
+                [5; 4]
+            );
+            assert!(Base::find_duplicate_types(&data(&acc)).is_empty());
+        }
+    }
+
+    #[test]
+    fn protected_tlvs_move_only_with_their_authority() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+        let owner = make_acc([9; 32], [0; 32], 0, &[], 0);
+        let other = make_acc([8; 32], [0; 32], 0, &[], 0);
+        let unsigned = Err(ProgramError::MissingRequiredSignature);
+
+        unsafe {
+            Base::add_extension_with_authority(
+                &acc.info,
+                &payer.info,
+                &rent.info,
+                &A { v: [1; 4] },
+                &[9; 32],
+            )
+            .unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [2; 2] }).unwrap();
+            let before = data(&acc);
+
+            assert_eq!(Base::touch_extension::<A>(&acc.info, Kind::A), unsigned);
+            assert_eq!(
+                Base::touch_extension_authorized::<A>(&acc.info, &other.info, Kind::A),
+                unsigned
+            );
+            assert_eq!(
+                Base::sort_extensions_by(&acc.info, |ext_type, _| u64::from(2 - ext_type)),
+                unsigned
+            );
+            assert_eq!(
+                Base::sort_extensions_by_authorized(&acc.info, &other.info, |ext_type, _| {
+                    u64::from(2 - ext_type)
+                }),
+                unsigned
+            );
+            assert_eq!(data(&acc), before);
+
+            // sorting that leaves the protected TLV in place needs no authority
+            Base::sort_extensions_by(&acc.info, |ext_type, _| u64::from(ext_type)).unwrap();
+            assert_eq!(data(&acc), before);
+
+            Base::touch_extension_authorized::<A>(&acc.info, &owner.info, Kind::A).unwrap();
+            assert_eq!(
+                Base::get_extension_variants(&acc.info),
+                Some(vec![Kind::C, Kind::A])
+            );
+
+            // C moves last too, but only A needs its authority
+            Base::sort_extensions_by_authorized(&acc.info, &owner.info, |ext_type, _| {
+                u64::from(ext_type)
+            })
+            .unwrap();
+            assert_eq!(data(&acc), before);
+        }
+    }
+
+    #[test]
+    fn replace_extension_refuses_a_type_stored_elsewhere() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &B { v: [2; 8] }).unwrap();
+            let before = data(&acc);
+
+            assert_eq!(
+                Base::replace_extension::<A, B>(
+                    &acc.info,
+                    &payer.info,
+                    &rent.info,
+                    Kind::A,
+                    &B { v: [3; 8] }
+                ),
+                Err(StateExtensionError::DuplicateExtension.into())
+            );
+            assert_eq!(data(&acc), before);
+
+            // the same type in its own slot is a plain rewrite
+            Base::replace_extension::<B, B>(
+                &acc.info,
+                &payer.info,
+                &rent.info,
+                Kind::B,
+                &B { v: [4; 8] },
+            )
+            .unwrap();
+            assert_eq!(
+                data(&acc)[10..],
+                account_bytes(&[(0, 0, &[1; 4]), (1, 0, &[4; 8])])[10..]
+            );
+        }
+    }
+
+    #[test]
+    fn a_raw_region_is_not_free_space() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Base::append_raw_region(&acc.info, &payer.info, &rent.info, &[5; 24]).unwrap();
+        }
+
+        assert_eq!(Base::free_trailing_bytes(&acc.info), 0);
+        assert!(!Base::has_trailing_padding(&acc.info));
+        assert_eq!(
+            Base::extension_region_end(&data(&acc)),
+            Some(data(&acc).len())
+        );
+
+        // the raw region moves with the TLVs
+        let region = Base::export_extension_region(&data(&acc)).unwrap();
+        let (target, _, _) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::import_extension_region(&target.info, &payer.info, &rent.info, &region).unwrap();
+        }
+        assert_eq!(data(&target), data(&acc));
+        assert_eq!(
+            Base::raw_region(&target.info, 24).as_deref(),
+            Some(&[5; 24][..])
+        );
+
+        assert_eq!(
+            unsafe {
+                Base::import_extension_region(
+                    &target.info,
+                    &payer.info,
+                    &rent.info,
+                    &region[..region.len() - 1],
+                )
+            },
+            Err(ProgramError::InvalidArgument)
+        );
+
+        // only the zero tail behind a raw region is free
+        let (acc, payer, rent) = accounts(&preallocated(&[(0, 0, &[1; 4])], 40));
+
+        unsafe {
+            Base::append_raw_region(&acc.info, &payer.info, &rent.info, &[5; 24]).unwrap();
+        }
+        assert_eq!(data(&acc).len(), 10 + 8 + 8 + 40);
+        assert_eq!(Base::free_trailing_bytes(&acc.info), 12);
+    }
+
+    #[test]
+    fn readers_agree_with_add_on_a_preallocated_base_only_account() {
+        let mut bytes = vec![3u8; 10];
+        bytes.resize(30, 0);
+        let (acc, payer, rent) = accounts(&bytes);
+
+        assert!(Base::is_extensible(&acc.info));
+        assert_eq!(Base::check_no_overlap(&bytes), Ok(()));
+        assert_eq!(Base::first_malformed_tlv(&bytes), None);
+        assert_eq!(Base::free_trailing_bytes(&acc.info), 20);
+        assert!(Base::has_trailing_padding(&acc.info));
+        assert_eq!(
+            Base::try_get_extension_variants::<Kind>(&acc.info),
+            Ok(vec![])
+        );
+        assert_eq!(Base::detect_format(&bytes), FormatVersion::BaseOnly);
+        assert_eq!(Base::next_write_offset(&acc.info), 10);
+        assert_eq!(Base::export_extension_region(&bytes), Some(vec![]));
+
+        let mut garbage = bytes.clone();
+        garbage[20] = 1;
+        let corrupt = make_acc([1; 32], OWNER, 1, &garbage, 0);
+
+        assert!(!Base::is_extensible(&corrupt.info));
+        assert!(Base::check_no_overlap(&garbage).is_err());
+        assert_eq!(Base::first_malformed_tlv(&garbage), Some(10));
+        assert_eq!(Base::free_trailing_bytes(&corrupt.info), 0);
+        assert!(Base::try_get_extension_variants::<Kind>(&corrupt.info).is_err());
+        assert_eq!(Base::detect_format(&garbage), FormatVersion::Unknown);
+        assert!(
+            unsafe {
+                Base::add_extension(&corrupt.info, &payer.info, &rent.info, &A { v: [1; 4] })
+            }
+            .is_err()
+        );
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+        }
+        assert_eq!(data(&acc).len(), 30);
+        assert!(Base::is_extensible(&acc.info));
+        assert_eq!(Base::free_trailing_bytes(&acc.info), 4);
+        assert_eq!(
+            Base::try_get_extension_variants::<Kind>(&acc.info),
+            Ok(vec![Kind::A])
+        );
+        assert_eq!(Base::detect_format(&data(&acc)), FormatVersion::Original);
+    }
+
+    #[test]
+    fn paging_counts_variants_not_tlvs() {
+        let bytes = account_bytes(&[
+            (0, 0, &[1; 4]),
+            (9, 0, &[0; 3]),
+            (1, 0, &[2; 8]),
+            (2, 0, &[3; 2]),
+        ]);
+        let acc = make_acc([1; 32], OWNER, 1, &bytes, 0);
+
+        assert_eq!(
+            Base::get_extension_variants_paged::<Kind>(&acc.info, 0, 2),
+            Some(vec![Kind::A, Kind::B])
+        );
+        assert_eq!(
+            Base::get_extension_variants_paged::<Kind>(&acc.info, 2, 2),
+            Some(vec![Kind::C])
+        );
+        assert_eq!(
+            Base::get_extension_variants_paged::<Kind>(&acc.info, 3, 2),
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn max_extensions_holds_on_every_add_path() {
+        struct Pair;
+
+        impl StateExtension for Pair {
+            const BASE_STATE_LEN: usize = 10;
+            const OWNER_PROGRAM: Pubkey = OWNER;
+            const MAX_EXTENSIONS: u8 = 2;
+            const EXT_START_MARKER: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+        }
+
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Pair::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Pair::add_extension(&acc.info, &payer.info, &rent.info, &B { v: [2; 8] }).unwrap();
+            assert_eq!(
+                Pair::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [3; 2] }),
+                Err(StateExtensionError::TooManyExtensions.into())
+            );
+        }
+        assert_eq!(data(&acc).len(), 10 + 8 + 8 + 12);
+
+        let (fresh, payer, rent) = accounts(&[3; 10]);
+        let three = region(&[(0, &[1; 4]), (1, &[2; 8]), (2, &[3; 2])]);
+        let twice = region(&[(0, &[1; 4]), (0, &[2; 4])]);
+
+        unsafe {
+            assert_eq!(
+                Pair::import_extension_region(&fresh.info, &payer.info, &rent.info, &three),
+                Err(StateExtensionError::TooManyExtensions.into())
+            );
+            assert_eq!(
+                Pair::import_extension_region(&fresh.info, &payer.info, &rent.info, &twice),
+                Err(StateExtensionError::DuplicateExtension.into())
+            );
+        }
+        assert_eq!(data(&fresh).len(), 10);
+    }
+
+    #[test]
+    fn update_extensions_refuses_a_missing_type_before_writing() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &B { v: [2; 8] }).unwrap();
+            let before = data(&acc);
+
+            assert_eq!(
+                Base::update_extensions(&acc.info, &[(0, &[5; 4]), (2, &[6; 2])]),
+                Err(StateExtensionError::ExtensionNotFound.into())
+            );
+            assert_eq!(data(&acc), before);
+
+            Base::update_extensions(&acc.info, &[(0, &[5; 4]), (1, &[6; 8])]).unwrap();
+            assert_eq!(
+                Base::get_extension::<A>(&acc.info, Kind::A).unwrap().ext.v,
+                [5; 4]
+            );
+            assert_eq!(
+                Base::get_extension::<B>(&acc.info, Kind::B).unwrap().ext.v,
+                [6; 8]
+            );
+        }
+    }
+
+    #[test]
+    fn a_tlv_past_the_realloc_limit_needs_a_preallocated_tail() {
+        #[repr(C)]
+        struct Big {
+            v: [u8; 12_000],
+        }
+
+        impl Extension for Big {
+            const LEN: u16 = 12_000;
+            type ExtensionEnum = Kind;
+            fn ext_type() -> u8 {
+                0
+            }
+        }
+
+        let big = Big { v: [7; 12_000] };
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            assert_eq!(
+                Base::add_extension(&acc.info, &payer.info, &rent.info, &big),
+                Err(StateExtensionError::ExtensionTooLarge.into())
+            );
+        }
+        assert_eq!(data(&acc).len(), 10);
+
+        let mut bytes = vec![3u8; 10];
+        bytes.resize(10 + 8 + 4 + 12_000, 0);
+        let preallocated = make_acc([1; 32], OWNER, 1_000_000_000, &bytes, 0);
+
+        unsafe {
+            Base::add_extension(&preallocated.info, &payer.info, &rent.info, &big).unwrap();
+            assert_eq!(
+                Base::get_extension::<Big>(&preallocated.info, Kind::A)
+                    .unwrap()
+                    .ext
+                    .v,
+                [7; 12_000]
+            );
+        }
+        assert_eq!(data(&preallocated).len(), bytes.len());
+    }
+
+    #[test]
+    fn update_variable_extension_moves_the_later_tlvs() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &B { v: [2; 8] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [3; 2] }).unwrap();
+        }
+        assert_eq!(data(&acc).len(), 44);
+
+        let paid = payer.info.lamports();
+
+        unsafe {
+            Base::update_variable_extension(&acc.info, &payer.info, &rent.info, Kind::A, &[9; 10])
+                .unwrap();
+        }
+        assert_eq!(
+            data(&acc)[10..],
+            account_bytes(&[(0, 0, &[9; 10]), (1, 0, &[2; 8]), (2, 0, &[3; 2])])[10..]
+        );
+        assert_eq!(payer.info.lamports(), paid);
+
+        unsafe {
+            Base::update_variable_extension(&acc.info, &payer.info, &rent.info, Kind::A, &[8])
+                .unwrap();
+            assert_eq!(
+                Base::get_extension::<B>(&acc.info, Kind::B).unwrap().ext.v,
+                [2; 8]
+            );
+            assert_eq!(
+                Base::get_extension::<C>(&acc.info, Kind::C).unwrap().ext.v,
+                [3; 2]
+            );
+        }
+        assert_eq!(
+            data(&acc)[10..],
+            account_bytes(&[(0, 0, &[8]), (1, 0, &[2; 8]), (2, 0, &[3; 2])])[10..]
+        );
+        assert_eq!(data(&acc).len(), 41);
+        assert_eq!(
+            payer.info.lamports(),
+            paid + minimum_balance(&rent, 50) - minimum_balance(&rent, 41)
+        );
+    }
+
+    #[test]
+    fn truncate_extension_refunds_the_dropped_bytes() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(
+                &acc.info,
+                &payer.info,
+                &rent.info,
+                &B {
+                    v: [1, 2, 3, 4, 5, 6, 7, 8],
+                },
+            )
+            .unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [9; 2] }).unwrap();
+        }
+        let paid = payer.info.lamports();
+
+        unsafe {
+            assert_eq!(
+                Base::truncate_extension(&acc.info, &payer.info, &rent.info, Kind::B, 8),
+                Err(ProgramError::InvalidArgument)
+            );
+            Base::truncate_extension(&acc.info, &payer.info, &rent.info, Kind::B, 3).unwrap();
+            assert_eq!(
+                Base::get_extension::<C>(&acc.info, Kind::C).unwrap().ext.v,
+                [9; 2]
+            );
+        }
+        assert_eq!(
+            data(&acc)[10..],
+            account_bytes(&[(1, 0, &[1, 2, 3]), (2, 0, &[9; 2])])[10..]
+        );
+        assert_eq!(data(&acc).len(), 10 + 8 + 7 + 6);
+        assert_eq!(
+            payer.info.lamports(),
+            paid + minimum_balance(&rent, 36) - minimum_balance(&rent, 31)
+        );
+    }
+
+    #[test]
+    fn migrate_marker_resizes_around_the_tlvs() {
+        fn with_marker(marker: &[u8]) -> Vec<u8> {
+            let mut bytes = vec![3u8; 10];
+            bytes.extend_from_slice(marker);
+            bytes.extend_from_slice(&region(&[(0, &[1; 4]), (2, &[2; 2])])[8..]);
+            bytes
+        }
+
+        let (acc, payer, rent) = accounts(&with_marker(&[9; 4]));
+        let paid = payer.info.lamports();
+
+        unsafe {
+            assert_eq!(
+                Base::migrate_marker(&acc.info, &payer.info, &rent.info, &[8; 4]),
+                Err(ProgramError::InvalidAccountData)
+            );
+            Base::migrate_marker(&acc.info, &payer.info, &rent.info, &[9; 4]).unwrap();
+            assert_eq!(
+                Base::get_extension::<C>(&acc.info, Kind::C).unwrap().ext.v,
+                [2; 2]
+            );
+        }
+        assert_eq!(data(&acc), with_marker(Base::EXT_START_MARKER));
+        assert_eq!(payer.info.lamports(), paid);
+
+        let (acc, payer, rent) = accounts(&with_marker(&[9; 12]));
+        let paid = payer.info.lamports();
+
+        unsafe {
+            Base::migrate_marker(&acc.info, &payer.info, &rent.info, &[9; 12]).unwrap();
+        }
+        assert_eq!(data(&acc), with_marker(Base::EXT_START_MARKER));
+        assert_eq!(
+            payer.info.lamports(),
+            paid + minimum_balance(&rent, 10 + 12 + 14) - minimum_balance(&rent, 10 + 8 + 14)
+        );
+    }
+
+    #[test]
+    fn insert_base_bytes_shifts_the_region() {
+        struct Wide;
+
+        impl StateExtension for Wide {
+            const BASE_STATE_LEN: usize = 12;
+            const OWNER_PROGRAM: Pubkey = OWNER;
+            const MAX_EXTENSIONS: u8 = 5;
+            const EXT_START_MARKER: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+        }
+
+        let mut bytes = account_bytes(&[(0, 0, &[1; 4]), (2, 0, &[2; 2])]);
+        bytes[..10].copy_from_slice(&[3; 10]);
+        let (acc, payer, rent) = accounts(&bytes);
+        let paid = payer.info.lamports();
+
+        unsafe {
+            assert_eq!(
+                Wide::insert_base_bytes(&acc.info, &payer.info, &rent.info, 11, &[8, 8]),
+                Err(ProgramError::InvalidArgument)
+            );
+            Wide::insert_base_bytes(&acc.info, &payer.info, &rent.info, 4, &[8, 8]).unwrap();
+            assert_eq!(
+                Wide::get_extension::<A>(&acc.info, Kind::A).unwrap().ext.v,
+                [1; 4]
+            );
+            assert_eq!(
+                Wide::get_extension::<C>(&acc.info, Kind::C).unwrap().ext.v,
+                [2; 2]
+            );
+        }
+        assert_eq!(data(&acc).len(), bytes.len() + 2);
+        assert_eq!(data(&acc)[..12], [3, 3, 3, 3, 8, 8, 3, 3, 3, 3, 3, 3]);
+        assert_eq!(data(&acc)[12..], bytes[10..]);
+        assert_eq!(payer.info.lamports(), paid);
+    }
+
+    #[test]
+    fn append_raw_region_stays_behind_later_tlvs() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+        let paid = payer.info.lamports();
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Base::append_raw_region(&acc.info, &payer.info, &rent.info, &[5; 24]).unwrap();
+            assert_eq!(
+                Base::append_raw_region(&acc.info, &payer.info, &rent.info, &[6; 2]),
+                Err(ProgramError::InvalidArgument)
+            );
+        }
+        assert_eq!(data(&acc).len(), 10 + 8 + 8 + 4 + 24);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [2; 2] }).unwrap();
+            assert_eq!(
+                Base::get_extension::<A>(&acc.info, Kind::A).unwrap().ext.v,
+                [1; 4]
+            );
+            assert_eq!(
+                Base::get_extension::<C>(&acc.info, Kind::C)
+                    .unwrap()
+                    .position,
+                26
+            );
+        }
+        assert_eq!(data(&acc).len(), 10 + 8 + 8 + 6 + 4 + 24);
+        assert_eq!(
+            Base::raw_region(&acc.info, 24).as_deref(),
+            Some(&[5; 24][..])
+        );
+        assert_eq!(payer.info.lamports(), paid);
+    }
+
+    #[test]
+    fn try_get_extension_variants_tells_the_outcomes_apart() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+        assert_eq!(
+            Base::try_get_extension_variants::<Kind>(&acc.info),
+            Ok(vec![])
+        );
+        assert_eq!(Base::get_extension_variants::<Kind>(&acc.info), None);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [1; 2] }).unwrap();
+        }
+        assert_eq!(
+            Base::try_get_extension_variants::<Kind>(&acc.info),
+            Ok(vec![Kind::C])
+        );
+
+        let foreign = make_acc([1; 32], [8; 32], 1, &data(&acc), 0);
+        assert_eq!(
+            Base::try_get_extension_variants::<Kind>(&foreign.info),
+            Err(ProgramError::IllegalOwner)
+        );
+
+        let mut corrupt = data(&acc);
+        corrupt[12] = 0xff;
+        let corrupt = make_acc([1; 32], OWNER, 1, &corrupt, 0);
+        assert_eq!(
+            Base::try_get_extension_variants::<Kind>(&corrupt.info),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn extension_array_element_indexes_past_the_header() {
+        let mut payload = vec![2, 0];
+        payload.extend_from_slice(&[4; 32]);
+        payload.extend_from_slice(&[5; 32]);
+        let bytes = account_bytes(&[(0, 0, &[1; 4]), (1, 0, &payload)]);
+
+        let element = |index| Base::extension_array_element::<B, Pubkey>(&bytes, Kind::B, 2, index);
+
+        assert_eq!(element(0), Some(&[4; 32]));
+        assert_eq!(element(1), Some(&[5; 32]));
+        assert_eq!(element(2), None);
+        assert_eq!(element(usize::MAX), None);
+        assert_eq!(
+            Base::extension_array_element::<C, Pubkey>(&bytes, Kind::C, 2, 0),
+            None
+        );
+    }
+
+    #[test]
+    fn is_valid_owner_accepts_a_second_program() {
+        struct Migrating;
+
+        impl StateExtension for Migrating {
+            const BASE_STATE_LEN: usize = 10;
+            const OWNER_PROGRAM: Pubkey = OWNER;
+            const MAX_EXTENSIONS: u8 = 5;
+            const EXT_START_MARKER: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+
+            fn is_valid_owner(owner: &Pubkey) -> bool {
+                owner == &OWNER || owner == &[6; 32]
+            }
+        }
+
+        let bytes = account_bytes(&[(2, 0, &[1; 2])]);
+
+        for owner in [OWNER, [6; 32]] {
+            let (acc, payer, rent) = accounts(&bytes);
+            unsafe { acc.info.assign(&owner) };
+
+            unsafe {
+                Migrating::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [2; 4] })
+                    .unwrap();
+                assert_eq!(
+                    Migrating::get_extension::<C>(&acc.info, Kind::C).map(|info| info.ext.v),
+                    Some([1; 2])
+                );
+            }
+            assert!(Migrating::is_extensible(&acc.info));
+        }
+
+        let (acc, payer, rent) = accounts(&bytes);
+        unsafe { acc.info.assign(&[8; 32]) };
+
+        assert!(!Migrating::is_extensible(&acc.info));
+        assert_eq!(
+            Migrating::try_get_extension_variants::<Kind>(&acc.info),
+            Err(ProgramError::IllegalOwner)
+        );
+        assert_eq!(
+            unsafe {
+                Migrating::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [2; 4] })
+            },
+            Err(ProgramError::IllegalOwner)
+        );
+    }
+
+    #[test]
+    fn get_extension_at_reads_only_tlv_starts() {
+        let bytes = account_bytes(&[(0, 0, &[1; 4]), (2, 1, &[2; 2])]);
+
+        unsafe {
+            let a = Base::get_extension_at::<A>(&bytes, 18).unwrap();
+            assert_eq!(
+                (a.ext.v, a.state, a.len),
+                ([1; 4], ExtensionState::Initialized, 4)
+            );
+
+            let c = Base::get_extension_at::<C>(&bytes, 26).unwrap();
+            assert_eq!(
+                (c.ext.v, c.state, c.position),
+                ([2; 2], ExtensionState::Zerod, 26)
+            );
+
+            // the type at 18 is A's
+            assert!(Base::get_extension_at::<C>(&bytes, 18).is_none());
+            // mid TLV, inside A's payload
+            assert!(Base::get_extension_at::<A>(&bytes, 22).is_none());
+            // inside the marker, past the end
+            assert!(Base::get_extension_at::<A>(&bytes, 12).is_none());
+            assert!(Base::get_extension_at::<C>(&bytes, bytes.len()).is_none());
+            assert!(Base::get_extension_at::<C>(&bytes, usize::MAX).is_none());
+        }
+    }
+
+    #[test]
+    fn four_byte_and_empty_markers_parse() {
+        struct Four;
+
+        impl StateExtension for Four {
+            const BASE_STATE_LEN: usize = 10;
+            const OWNER_PROGRAM: Pubkey = OWNER;
+            const MAX_EXTENSIONS: u8 = 5;
+            const EXT_START_MARKER: &[u8] = &[9, 8, 7, 6];
+        }
+
+        struct Unmarked;
+
+        impl StateExtension for Unmarked {
+            const BASE_STATE_LEN: usize = 10;
+            const OWNER_PROGRAM: Pubkey = OWNER;
+            const MAX_EXTENSIONS: u8 = 5;
+            const EXT_START_MARKER: &[u8] = &[];
+        }
+
+        let (four, payer, rent) = accounts(&[3; 10]);
+        let (unmarked, _, _) = accounts(&[3; 10]);
+
+        unsafe {
+            Four::add_extension(&four.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Four::add_extension(&four.info, &payer.info, &rent.info, &C { v: [2; 2] }).unwrap();
+            Unmarked::add_extension(&unmarked.info, &payer.info, &rent.info, &A { v: [1; 4] })
+                .unwrap();
+            Unmarked::add_extension(&unmarked.info, &payer.info, &rent.info, &C { v: [2; 2] })
+                .unwrap();
+
+            assert_eq!(
+                data(&four)[10..],
+                [9, 8, 7, 6, 0, 0, 4, 0, 1, 1, 1, 1, 2, 0, 2, 0, 2, 2]
+            );
+            assert_eq!(data(&unmarked)[10..], data(&four)[14..]);
+
+            assert_eq!(
+                Four::get_extension::<C>(&four.info, Kind::C)
+                    .map(|info| (info.ext.v, info.position)),
+                Some(([2; 2], 22))
+            );
+            assert_eq!(
+                Unmarked::get_extension::<C>(&unmarked.info, Kind::C)
+                    .map(|info| (info.ext.v, info.position)),
+                Some(([2; 2], 18))
+            );
+        }
+
+        for variants in [
+            Four::try_get_extension_variants::<Kind>(&four.info),
+            Unmarked::try_get_extension_variants::<Kind>(&unmarked.info),
+        ] {
+            assert_eq!(variants, Ok(vec![Kind::A, Kind::C]));
+        }
+        assert!(Four::check_no_overlap(&data(&four)).is_ok());
+        assert!(Unmarked::check_no_overlap(&data(&unmarked)).is_ok());
+    }
+
+    #[test]
+    fn try_get_extension_surfaces_a_matching_tlv_that_does_not_decode() {
+        // C is stored with 3 bytes instead of its 2
+        let bytes = account_bytes(&[(0, 0, &[1; 4]), (2, 0, &[2; 3])]);
+
+        assert!(Base::get_extension_from_acc_data_unchecked::<C>(&bytes, Kind::C).is_none());
+        assert!(matches!(
+            Base::try_get_extension_from_acc_data::<C>(&bytes, Kind::C),
+            Err(ProgramError::InvalidAccountData)
+        ));
+
+        assert_eq!(
+            Base::try_get_extension_from_acc_data::<A>(&bytes, Kind::A)
+                .unwrap()
+                .map(|info| info.ext.v),
+            Some([1; 4])
+        );
+        assert!(matches!(
+            Base::try_get_extension_from_acc_data::<B>(&bytes, Kind::B),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn export_import_moves_the_region_between_accounts() {
+        let (source, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&source.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Base::add_extension(&source.info, &payer.info, &rent.info, &B { v: [2; 8] }).unwrap();
+            Base::zero_out_extension_data::<A>(&source.info, Kind::A).unwrap();
+        }
+
+        let region = Base::export_extension_region(&data(&source)).unwrap();
+        assert_eq!(region, data(&source)[10..]);
+
+        let (target, _, _) = accounts(&[4; 10]);
+
+        unsafe {
+            Base::import_extension_region(&target.info, &payer.info, &rent.info, &region).unwrap();
+
+            let a = Base::get_extension::<A>(&target.info, Kind::A).unwrap();
+            assert_eq!((a.ext.v, a.state), ([0; 4], ExtensionState::Zerod));
+            assert_eq!(
+                Base::get_extension::<B>(&target.info, Kind::B).map(|info| info.ext.v),
+                Some([2; 8])
+            );
+        }
+        assert_eq!(data(&target)[..10], [4; 10]);
+        assert!(Base::regions_equal(&data(&source), &data(&target)));
+
+        // a second import finds the marker of the first
+        assert_eq!(
+            unsafe {
+                Base::import_extension_region(&target.info, &payer.info, &rent.info, &region)
+            },
+            Err(StateExtensionError::MarkerAlreadyPresent.into())
+        );
+    }
+
+    #[test]
+    fn adds_to_a_closing_account_need_allow_revive() {
+        struct Reviving;
+
+        impl StateExtension for Reviving {
+            const BASE_STATE_LEN: usize = 10;
+            const OWNER_PROGRAM: Pubkey = OWNER;
+            const MAX_EXTENSIONS: u8 = 5;
+            const EXT_START_MARKER: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+            const ALLOW_REVIVE: bool = true;
+        }
+
+        let (_, payer, rent) = accounts(&[]);
+        let closing = make_acc([1; 32], OWNER, 0, &[3; 10], 64);
+
+        unsafe {
+            assert_eq!(
+                Base::add_extension(&closing.info, &payer.info, &rent.info, &A { v: [1; 4] }),
+                Err(StateExtensionError::AccountBeingClosed.into())
+            );
+            assert_eq!(
+                Base::reserve_extension::<A>(&closing.info, &payer.info, &rent.info),
+                Err(StateExtensionError::AccountBeingClosed.into())
+            );
+        }
+        assert_eq!(data(&closing), [3; 10]);
+
+        unsafe {
+            Reviving::add_extension(&closing.info, &payer.info, &rent.info, &A { v: [1; 4] })
+                .unwrap();
+        }
+        assert_eq!(
+            data(&closing)[10..],
+            account_bytes(&[(0, 0, &[1; 4])])[10..]
+        );
+    }
+
+    #[test]
+    fn validate_refuses_a_bad_field_before_writing() {
+        #[repr(C)]
+        struct Tagged {
+            tag: u8,
+            v: [u8; 3],
+        }
+
+        impl Extension for Tagged {
+            const LEN: u16 = 4;
+            type ExtensionEnum = Kind;
+            fn ext_type() -> u8 {
+                0
+            }
+
+            fn validate(&self) -> Result<(), ProgramError> {
+                match self.tag {
+                    7 => Ok(()),
+                    _ => Err(ProgramError::InvalidInstructionData),
+                }
+            }
+        }
+
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            assert_eq!(
+                Base::add_extension(
+                    &acc.info,
+                    &payer.info,
+                    &rent.info,
+                    &Tagged { tag: 1, v: [1; 3] }
+                ),
+                Err(ProgramError::InvalidInstructionData)
+            );
+            assert_eq!(data(&acc), [3; 10]);
+
+            Base::add_extension(
+                &acc.info,
+                &payer.info,
+                &rent.info,
+                &Tagged { tag: 7, v: [1; 3] },
+            )
+            .unwrap();
+            assert_eq!(
+                Base::update_extension(&acc.info, Kind::A, &Tagged { tag: 0, v: [2; 3] }),
+                Err(ProgramError::InvalidInstructionData)
+            );
+
+            let tagged = Base::get_extension::<Tagged>(&acc.info, Kind::A).unwrap();
+            assert_eq!((tagged.ext.tag, tagged.ext.v), (7, [1; 3]));
+        }
+    }
+
+    #[test]
+    fn an_undersized_account_needs_its_base_state_first() {
+        for len in [0, 4] {
+            let (acc, payer, rent) = accounts(&vec![3; len]);
+
+            unsafe {
+                assert_eq!(
+                    Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }),
+                    Err(StateExtensionError::BaseStateNotInitialized.into())
+                );
+                assert_eq!(
+                    Base::init_base_then_add_extension(
+                        &acc.info,
+                        &payer.info,
+                        &rent.info,
+                        &[5; 9],
+                        &A { v: [1; 4] }
+                    ),
+                    Err(ProgramError::InvalidArgument)
+                );
+                Base::init_base_then_add_extension(
+                    &acc.info,
+                    &payer.info,
+                    &rent.info,
+                    &[5; 10],
+                    &A { v: [1; 4] },
+                )
+                .unwrap();
+                assert_eq!(
+                    Base::init_base_then_add_extension(
+                        &acc.info,
+                        &payer.info,
+                        &rent.info,
+                        &[5; 10],
+                        &C { v: [2; 2] }
+                    ),
+                    Err(ProgramError::AccountAlreadyInitialized)
+                );
+            }
+
+            let mut expected = account_bytes(&[(0, 0, &[1; 4])]);
+            expected[..10].copy_from_slice(&[5; 10]);
+            assert_eq!(data(&acc), expected);
+        }
+    }
+
+    #[test]
+    fn as_prefix_overlays_a_shorter_version() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(
+                &acc.info,
+                &payer.info,
+                &rent.info,
+                &B {
+                    v: [1, 2, 3, 4, 5, 6, 7, 8],
+                },
+            )
+            .unwrap();
+
+            let b = Base::get_extension::<B>(&acc.info, Kind::B).unwrap();
+
+            assert_eq!(b.as_prefix::<[u8; 3]>(), Some(&[1, 2, 3]));
+            assert_eq!(b.as_prefix::<[u8; 8]>(), Some(&b.ext.v));
+            assert_eq!(b.as_prefix::<[u8; 9]>(), None);
+            assert_eq!(b.as_prefix::<u8>(), Some(&1));
+        }
+    }
+
+    #[test]
+    fn with_base_and_extension_compares_across_one_borrow() {
+        #[repr(C)]
+        struct Header {
+            authority: [u8; 4],
+            rest: [u8; 6],
+        }
+
+        let mut base = [9u8; 10];
+        base[4..].copy_from_slice(&[0; 6]);
+        let (acc, payer, rent) = accounts(&base);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [9; 4] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [1; 2] }).unwrap();
+
+            assert_eq!(
+                Base::with_base_and_extension::<Header, A, _, _>(
+                    &acc.info,
+                    Kind::A,
+                    |header, a| {
+                        // the data stays borrowed while f runs
+                        assert!(acc.info.try_borrow_mut_data().is_err());
+                        (header.authority == a.v, header.rest)
+                    }
+                ),
+                Some((true, [0; 6]))
+            );
+            assert_eq!(
+                Base::with_base_and_extension::<Header, C, _, _>(
+                    &acc.info,
+                    Kind::C,
+                    |header, c| { header.authority[..2] == c.v }
+                ),
+                Some(false)
+            );
+            assert_eq!(
+                Base::with_base_and_extension::<[u8; 11], A, _, _>(&acc.info, Kind::A, |_, _| ()),
+                None
+            );
+            assert_eq!(
+                Base::with_base_and_extension::<Header, B, _, _>(&acc.info, Kind::B, |_, _| ()),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn strict_variants_refuse_a_malformed_trailing_tlv() {
+        let mut bytes = account_bytes(&[(0, 0, &[1; 4]), (2, 0, &[2; 2])]);
+        assert_eq!(
+            Base::try_get_extension_variants_from_data::<Kind>(&bytes),
+            Ok(vec![Kind::A, Kind::C])
+        );
+
+        // a B header claiming 9 bytes with only 3 behind it
+        bytes.extend_from_slice(&[1, 0, 9, 0, 5, 5, 5]);
+        assert_eq!(
+            Base::try_get_extension_variants_from_data::<Kind>(&bytes),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(
+            Base::get_extension_variants_from_acc_data_uncheked::<Kind>(&bytes),
+            Some(vec![Kind::A, Kind::C])
+        );
+    }
+
+    #[test]
+    fn adds_stop_at_the_account_size_limit() {
+        struct Huge;
+
+        impl StateExtension for Huge {
+            const BASE_STATE_LEN: usize = MAX_PERMITTED_DATA_LENGTH - 16;
+            const OWNER_PROGRAM: Pubkey = OWNER;
+            const MAX_EXTENSIONS: u8 = 5;
+            const EXT_START_MARKER: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+        }
+
+        let (acc, payer, rent) = accounts(&vec![3; Huge::BASE_STATE_LEN]);
+
+        unsafe {
+            // marker, header and 4 bytes of A end exactly at the limit
+            Huge::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            assert_eq!(acc.info.data_len(), MAX_PERMITTED_DATA_LENGTH);
+
+            assert_eq!(
+                Huge::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [2; 2] }),
+                Err(StateExtensionError::AccountSizeLimitExceeded.into())
+            );
+        }
+        assert_eq!(acc.info.data_len(), MAX_PERMITTED_DATA_LENGTH);
+        assert_eq!(
+            Huge::try_get_extension_variants::<Kind>(&acc.info),
+            Ok(vec![Kind::A])
+        );
+    }
+
+    #[test]
+    fn get_extension_strict_reports_a_stored_length_mismatch() {
+        let bytes = account_bytes(&[(0, 0, &[1; 4]), (2, 0, &[2; 3])]);
+        let acc = make_acc([1; 32], OWNER, 1, &bytes, 0);
+
+        unsafe {
+            assert!(Base::get_extension::<C>(&acc.info, Kind::C).is_none());
+            assert!(matches!(
+                Base::get_extension_strict::<C>(&acc.info, Kind::C),
+                Err(e) if e == StateExtensionError::ExtensionLengthMismatch.into()
+            ));
+
+            let a = Base::get_extension_strict::<A>(&acc.info, Kind::A)
+                .unwrap()
+                .unwrap();
+            assert_eq!((a.ext.v, a.len), ([1; 4], 4));
+            assert!(matches!(
+                Base::get_extension_strict::<B>(&acc.info, Kind::B),
+                Ok(None)
+            ));
+
+            let foreign = make_acc([1; 32], [8; 32], 1, &bytes, 0);
+            assert!(matches!(
+                Base::get_extension_strict::<A>(&foreign.info, Kind::A),
+                Err(ProgramError::IllegalOwner)
+            ));
+        }
+    }
+
+    #[test]
+    fn extension_writer_fills_the_payload_in_place() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [1; 2] }).unwrap();
+
+            let mut writer =
+                Base::extension_writer::<B>(&acc.info, &payer.info, &rent.info).unwrap();
+            assert_eq!(writer.position(), 24);
+            assert_eq!(writer.payload(), [0; 8]);
+
+            writer.payload()[..4].copy_from_slice(&[1, 2, 3, 4]);
+            writer.payload()[4..].copy_from_slice(&[5, 6, 7, 8]);
+
+            // still borrowed and Zerod until finish
+            assert!(acc.info.try_borrow_data().is_err());
+            drop(writer);
+
+            let b = Base::get_extension::<B>(&acc.info, Kind::B).unwrap();
+            assert_eq!(
+                (b.ext.v, b.state),
+                ([1, 2, 3, 4, 5, 6, 7, 8], ExtensionState::Zerod)
+            );
+
+            Base::remove_extension::<B>(&acc.info, &payer.info, &rent.info, Kind::B).unwrap();
+
+            let mut writer =
+                Base::extension_writer::<B>(&acc.info, &payer.info, &rent.info).unwrap();
+            writer.payload().copy_from_slice(&[9; 8]);
+            writer.finish().unwrap();
+
+            let b = Base::get_extension::<B>(&acc.info, Kind::B).unwrap();
+            assert_eq!((b.ext.v, b.state), ([9; 8], ExtensionState::Initialized));
+            assert_eq!(
+                Base::get_extension::<C>(&acc.info, Kind::C).unwrap().ext.v,
+                [1; 2]
+            );
+        }
+    }
+
+    #[test]
+    fn detect_format_classifies_each_layout() {
+        assert_eq!(Base::detect_format(&[3; 10]), FormatVersion::BaseOnly);
+        assert_eq!(
+            Base::detect_format(&account_bytes(&[(0, 0, &[1; 4]), (2, 1, &[2; 2])])),
+            FormatVersion::Original
+        );
+
+        let (aligned, payer, rent) = accounts(&[3; 10]);
+        let (protected, _, _) = accounts(&[3; 10]);
+
+        unsafe {
+            // the payload would start at 22, two padding bytes put it on 24
+            Base::add_extension(&aligned.info, &payer.info, &rent.info, &Aligned { v: 1 }).unwrap();
+            Base::add_extension_with_authority(
+                &protected.info,
+                &payer.info,
+                &rent.info,
+                &A { v: [1; 4] },
+                &[9; 32],
+            )
+            .unwrap();
+        }
+        assert_eq!(Base::detect_format(&data(&aligned)), FormatVersion::Aligned);
+        assert_eq!(
+            Base::detect_format(&data(&protected)),
+            FormatVersion::WithAuthority
+        );
+
+        let mut undefined_state = account_bytes(&[(0, 0, &[1; 4])]);
+        undefined_state[19] = 0x09;
+        let mut truncated = account_bytes(&[(0, 0, &[1; 4])]);
+        truncated.extend_from_slice(&[2, 0, 9, 0]);
+        let mut unmarked = vec![3u8; 10];
+        unmarked.extend_from_slice(&[4; 6]);
+
+        for unknown in [undefined_state, truncated, unmarked] {
+            assert_eq!(Base::detect_format(&unknown), FormatVersion::Unknown);
+        }
+    }
+
+    #[test]
+    fn state_view_reads_several_times_from_one_borrow() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [2; 2] }).unwrap();
+        }
+
+        {
+            let view = StateView::<Base>::new(&acc.info).unwrap();
+
+            assert_eq!(view.get::<A>(Kind::A).map(|info| info.ext.v), Some([1; 4]));
+            assert_eq!(view.get::<C>(Kind::C).map(|info| info.ext.v), Some([2; 2]));
+            assert!(view.get::<B>(Kind::B).is_none());
+            assert!(view.has(Kind::A) && !view.has(Kind::B));
+            assert_eq!(view.count(), 2);
+            assert_eq!(view.variants::<Kind>(), vec![Kind::A, Kind::C]);
+            assert_eq!(view.data()[..10], [3; 10]);
+
+            // the view holds the borrow, writers wait for it
+            assert_eq!(
+                unsafe { Base::update_extension(&acc.info, Kind::A, &A { v: [5; 4] }) },
+                Err(StateExtensionError::AccountAlreadyBorrowed.into())
+            );
+        }
+
+        let foreign = make_acc([1; 32], [8; 32], 1, &data(&acc), 0);
+        let short = make_acc([1; 32], OWNER, 1, &[3; 4], 0);
+
+        assert!(matches!(
+            StateView::<Base>::new(&foreign.info),
+            Err(ProgramError::IllegalOwner)
+        ));
+        assert!(matches!(
+            StateView::<Base>::new(&short.info),
+            Err(e) if e == StateExtensionError::BaseStateNotInitialized.into()
+        ));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn index_map_matches_the_walk() {
+        let bytes = account_bytes(&[(0, 0, &[1; 4]), (1, 1, &[2; 8]), (2, 0, &[3; 2])]);
+        let index = Base::index_map(&bytes);
+
+        assert_eq!(index.len(), 3);
+        for tlv in Base::tlv_iter(&bytes).unwrap() {
+            assert_eq!(index[&tlv.ext_type], (tlv.position, tlv.len));
+        }
+        assert_eq!(index[&1], (26, 8));
+        assert!(Base::index_map(&[3; 10]).is_empty());
+    }
+
+    #[test]
+    fn diff_extensions_reports_an_add_a_removal_and_a_zero_out() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &B { v: [2; 8] }).unwrap();
+        }
+        let before = data(&acc);
+
+        unsafe {
+            Base::remove_extension::<A>(&acc.info, &payer.info, &rent.info, Kind::A).unwrap();
+            Base::zero_out_extension_data::<B>(&acc.info, Kind::B).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [3; 2] }).unwrap();
+        }
+        let after = data(&acc);
+
+        assert_eq!(
+            Base::diff_extensions::<Kind>(&before, &after),
+            ExtensionDiff {
+                added: vec![Kind::C],
+                removed: vec![Kind::A],
+                state_changed: vec![(Kind::B, ExtensionState::Initialized, ExtensionState::Zerod)],
+            }
+        );
+        assert_eq!(
+            Base::diff_extensions::<Kind>(&after, &after),
+            ExtensionDiff {
+                added: vec![],
+                removed: vec![],
+                state_changed: vec![],
+            }
+        );
+        assert_eq!(
+            Base::diff_extensions::<Kind>(&[3; 10], &before).added,
+            vec![Kind::A, Kind::B]
+        );
+    }
+
+    #[test]
+    fn get_extension_across_reads_each_account() {
+        let (first, payer, rent) = accounts(&[3; 10]);
+        let (second, _, _) = accounts(&[3; 10]);
+        let (third, _, _) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&first.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Base::add_extension(&second.info, &payer.info, &rent.info, &C { v: [2; 2] }).unwrap();
+            Base::add_extension(&third.info, &payer.info, &rent.info, &C { v: [3; 2] }).unwrap();
+            Base::add_extension(&third.info, &payer.info, &rent.info, &A { v: [4; 4] }).unwrap();
+        }
+
+        let accs = [&first.info, &second.info, &third.info];
+        let found: Vec<_> = unsafe { Base::get_extension_across::<A>(&accs, Kind::A) }
+            .into_iter()
+            .map(|info| info.map(|info| (info.ext.v, info.position)))
+            .collect();
+
+        assert_eq!(found, vec![Some(([1; 4], 18)), None, Some(([4; 4], 24))]);
+    }
+
+    #[test]
+    fn set_extension_state_follows_the_transition_hook() {
+        #[repr(C)]
+        struct Sealing {
+            v: [u8; 4],
+        }
+
+        impl Extension for Sealing {
+            const LEN: u16 = 4;
+            type ExtensionEnum = Kind;
+            fn ext_type() -> u8 {
+                0
+            }
+
+            // once Zerod it stays Zerod
+            fn is_valid_transition(from: &ExtensionState, to: &ExtensionState) -> bool {
+                !(*from == ExtensionState::Zerod && *to == ExtensionState::Initialized)
+            }
+        }
+
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            assert_eq!(
+                Base::set_extension_state::<Sealing>(&acc.info, Kind::A, ExtensionState::Zerod),
+                Err(StateExtensionError::ExtensionNotFound.into())
+            );
+
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &Sealing { v: [1; 4] })
+                .unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [2; 2] }).unwrap();
+
+            for state in [
+                ExtensionState::Initialized,
+                ExtensionState::Zerod,
+                ExtensionState::Zerod,
+            ] {
+                Base::set_extension_state::<Sealing>(&acc.info, Kind::A, state.clone()).unwrap();
+
+                let sealing = Base::get_extension::<Sealing>(&acc.info, Kind::A).unwrap();
+                assert_eq!((sealing.ext.v, sealing.state), ([1; 4], state));
+            }
+
+            assert_eq!(
+                Base::set_extension_state::<Sealing>(
+                    &acc.info,
+                    Kind::A,
+                    ExtensionState::Initialized
+                ),
+                Err(StateExtensionError::InvalidStateTransition.into())
+            );
+            assert_eq!(
+                Base::get_extension::<Sealing>(&acc.info, Kind::A)
+                    .unwrap()
+                    .state,
+                ExtensionState::Zerod
+            );
+
+            // C keeps the default hook, every transition is allowed
+            Base::set_extension_state::<C>(&acc.info, Kind::C, ExtensionState::Zerod).unwrap();
+            Base::set_extension_state::<C>(&acc.info, Kind::C, ExtensionState::Initialized)
+                .unwrap();
+            assert_eq!(
+                Base::get_extension::<C>(&acc.info, Kind::C).map(|info| (info.ext.v, info.state)),
+                Some(([2; 2], ExtensionState::Initialized))
+            );
+        }
+    }
+
+    #[test]
+    fn next_extension_after_walks_first_to_last() {
+        let bytes = account_bytes(&[(0, 0, &[1; 4]), (1, 1, &[2; 8]), (2, 0, &[3; 2])]);
+
+        let mut walked = vec![];
+        let mut position = 18;
+
+        while let Some((ext_type, state, next)) = Base::next_extension_after(&bytes, position) {
+            walked.push((ext_type, state));
+            position = next;
+        }
+
+        assert_eq!(
+            walked,
+            vec![(1, ExtensionState::Zerod), (2, ExtensionState::Initialized)]
+        );
+        assert_eq!(position, 38);
+        // not the start of a TLV
+        assert_eq!(Base::next_extension_after(&bytes, 20), None);
+    }
+
+    #[test]
+    fn add_extension_with_fills_the_reserved_payload() {
+        #[repr(C)]
+        struct Nonzero {
+            v: [u8; 2],
+        }
+
+        impl Extension for Nonzero {
+            const LEN: u16 = 2;
+            type ExtensionEnum = Kind;
+            fn ext_type() -> u8 {
+                2
+            }
+
+            fn validate(&self) -> Result<(), ProgramError> {
+                match self.v {
+                    [0, 0] => Err(ProgramError::InvalidInstructionData),
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            // bytes fill leaves alone stay zero
+            Base::add_extension_with::<B, _>(&acc.info, &payer.info, &rent.info, |payload| {
+                assert_eq!(payload, [0; 8]);
+                payload[2..5].copy_from_slice(&[7, 8, 9]);
+            })
+            .unwrap();
+
+            let b = Base::get_extension::<B>(&acc.info, Kind::B).unwrap();
+            assert_eq!(
+                (b.ext.v, b.state),
+                ([0, 0, 7, 8, 9, 0, 0, 0], ExtensionState::Initialized)
+            );
+
+            assert_eq!(
+                Base::add_extension_with::<Nonzero, _>(&acc.info, &payer.info, &rent.info, |_| ()),
+                Err(ProgramError::InvalidInstructionData)
+            );
+            assert_eq!(
+                Base::get_extension::<Nonzero>(&acc.info, Kind::C).map(|info| info.state),
+                Some(ExtensionState::Zerod)
+            );
+        }
+    }
+
+    #[test]
+    fn largest_extension_reports_the_longest_payload() {
+        let bytes = account_bytes(&[
+            (0, 0, &[1; 4]),
+            (1, 0, &[2; 8]),
+            (2, 0, &[3; 2]),
+            (3, 0, &[4; 8]),
+        ]);
+
+        // the first of two equally long payloads
+        assert_eq!(Base::largest_extension(&bytes), Some((1, 8)));
+        assert_eq!(
+            Base::largest_extension(&account_bytes(&[(2, 0, &[3; 2])])),
+            Some((2, 2))
+        );
+        assert_eq!(Base::largest_extension(&[3; 10]), None);
+    }
+
+    #[test]
+    fn copy_extension_payload_needs_room_for_the_payload() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(
+                &acc.info,
+                &payer.info,
+                &rent.info,
+                &B {
+                    v: [1, 2, 3, 4, 5, 6, 7, 8],
+                },
+            )
+            .unwrap();
+
+            let mut exact = [0; 8];
+            assert_eq!(
+                Base::copy_extension_payload(&acc.info, Kind::B, &mut exact),
+                Ok(8)
+            );
+            assert_eq!(exact, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+            let mut larger = [9; 10];
+            assert_eq!(
+                Base::copy_extension_payload(&acc.info, Kind::B, &mut larger),
+                Ok(8)
+            );
+            assert_eq!(larger, [1, 2, 3, 4, 5, 6, 7, 8, 9, 9]);
+
+            let mut small = [0; 7];
+            assert_eq!(
+                Base::copy_extension_payload(&acc.info, Kind::B, &mut small),
+                Err(ProgramError::InvalidArgument)
+            );
+            assert_eq!(small, [0; 7]);
+            assert_eq!(
+                Base::copy_extension_payload(&acc.info, Kind::A, &mut exact),
+                Err(StateExtensionError::ExtensionNotFound.into())
+            );
+        }
+    }
+
+    #[test]
+    fn compactable_rent_prices_the_zeroed_tlvs() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &B { v: [2; 8] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [3; 2] }).unwrap();
+        }
+        assert_eq!(Base::compactable_rent(&acc.info, &rent.info), 0);
+
+        unsafe {
+            Base::zero_out_extension_data::<B>(&acc.info, Kind::B).unwrap();
+            Base::zero_out_extension_data::<C>(&acc.info, Kind::C).unwrap();
+        }
+        assert_eq!(Base::compactable_bytes(&acc.info), 12 + 6);
+        assert_eq!(
+            Base::compactable_rent(&acc.info, &rent.info),
+            minimum_balance(&rent, 44) - minimum_balance(&rent, 44 - 18)
+        );
+
+        // removing them hands back what was previewed
+        let paid = payer.info.lamports();
+        unsafe {
+            Base::remove_extension::<B>(&acc.info, &payer.info, &rent.info, Kind::B).unwrap();
+            Base::remove_extension::<C>(&acc.info, &payer.info, &rent.info, Kind::C).unwrap();
+        }
+        assert_eq!(
+            payer.info.lamports() - paid,
+            minimum_balance(&rent, 44) - minimum_balance(&rent, 26)
+        );
+        assert_eq!(Base::compactable_rent(&acc.info, &rent.info), 0);
+    }
+
+    #[test]
+    fn strict_extension_types_refuse_an_undecodable_type() {
+        struct Strict;
+
+        impl StateExtension for Strict {
+            const BASE_STATE_LEN: usize = 10;
+            const OWNER_PROGRAM: Pubkey = OWNER;
+            const MAX_EXTENSIONS: u8 = 5;
+            const EXT_START_MARKER: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+            const STRICT_EXTENSION_TYPES: bool = true;
+        }
+
+        let known = account_bytes(&[(0, 0, &[1; 4]), (2, 0, &[2; 2])]);
+        // type 9 is no Kind, ahead of C
+        let unknown = account_bytes(&[(0, 0, &[1; 4]), (9, 0, &[5; 3]), (2, 0, &[2; 2])]);
+
+        assert!(matches!(
+            Strict::try_get_extension_from_acc_data::<B>(&known, Kind::B),
+            Ok(None)
+        ));
+        assert!(matches!(
+            Strict::try_get_extension_from_acc_data::<C>(&unknown, Kind::C),
+            Err(e) if e == StateExtensionError::InvalidExtensionType.into()
+        ));
+        // A comes before the unknown type and is found first
+        assert!(
+            Strict::try_get_extension_from_acc_data::<A>(&unknown, Kind::A)
+                .unwrap()
+                .is_some()
+        );
+
+        assert_eq!(
+            Base::try_get_extension_from_acc_data::<C>(&unknown, Kind::C)
+                .unwrap()
+                .map(|info| info.ext.v),
+            Some([2; 2])
+        );
+        assert!(matches!(
+            Base::try_get_extension_from_acc_data::<B>(&unknown, Kind::B),
+            Ok(None)
+        ));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_payloads_round_trip_smaller() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        let mut blob = vec![b' '; 120];
+        blob.extend_from_slice(b"{\"name\":\"x\"}");
+        blob.extend_from_slice(&[0; 180]);
+
+        unsafe {
+            Base::add_extension_compressed(&acc.info, &payer.info, &rent.info, Kind::B, &blob)
+                .unwrap();
+            Base::add_extension_compressed(
+                &acc.info,
+                &payer.info,
+                &rent.info,
+                Kind::C,
+                &[1, 2, 3, 4],
+            )
+            .unwrap();
+        }
+
+        let data = data(&acc);
+        let stored = Base::find_tlv(&data, 1).unwrap().payload;
+        assert_eq!(stored[..3], [1, 56, 1]);
+        assert!(stored.len() < blob.len());
+
+        // coding would not make it smaller, so it is kept as is
+        assert_eq!(
+            Base::find_tlv(&data, 2).unwrap().payload,
+            [0, 4, 0, 1, 2, 3, 4]
+        );
+
+        assert_eq!(
+            Base::get_extension_decompressed(&data, Kind::B),
+            Ok(Some(blob))
+        );
+        assert_eq!(
+            Base::get_extension_decompressed(&data, Kind::C),
+            Ok(Some(vec![1, 2, 3, 4]))
+        );
+        assert_eq!(Base::get_extension_decompressed(&data, Kind::A), Ok(None));
+    }
+
+    #[test]
+    fn extension_payload_eq_compares_byte_for_byte() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1, 2, 3, 4] })
+                .unwrap();
+        }
+
+        assert!(Base::extension_payload_eq(
+            &acc.info,
+            Kind::A,
+            &[1, 2, 3, 4]
+        ));
+        assert!(!Base::extension_payload_eq(
+            &acc.info,
+            Kind::A,
+            &[1, 2, 3, 5]
+        ));
+        assert!(!Base::extension_payload_eq(&acc.info, Kind::A, &[1, 2, 3]));
+        assert!(!Base::extension_payload_eq(
+            &acc.info,
+            Kind::A,
+            &[1, 2, 3, 4, 0]
+        ));
+        assert!(!Base::extension_payload_eq(&acc.info, Kind::B, &[]));
+
+        let foreign = make_acc([1; 32], [8; 32], 1, &data(&acc), 0);
+        assert!(!Base::extension_payload_eq(
+            &foreign.info,
+            Kind::A,
+            &[1, 2, 3, 4]
+        ));
+    }
+
+    #[test]
+    fn resolve_offset_ref_follows_a_stored_offset_to_a_sibling() {
+        // B links to C, which starts 20 bytes into the region: 8 marker bytes
+        // and B's 12
+        let bytes = account_bytes(&[(1, 0, &[20, 0, 0, 0, 0, 0, 0, 0]), (2, 1, &[7; 2])]);
+
+        let link = Base::read_field::<Kind, u16>(&bytes, Kind::B, 0).unwrap();
+        assert_eq!(
+            Base::resolve_offset_ref(&bytes, link as usize),
+            Some((2, ExtensionState::Zerod, &[7; 2][..]))
+        );
+        assert_eq!(
+            Base::resolve_offset_ref(&bytes, 8),
+            Some((
+                1,
+                ExtensionState::Initialized,
+                &[20, 0, 0, 0, 0, 0, 0, 0][..]
+            ))
+        );
+
+        // into a payload, the marker, past the end
+        for offset in [12, 0, 26, usize::MAX] {
+            assert_eq!(Base::resolve_offset_ref(&bytes, offset), None);
+        }
+    }
+
+    #[test]
+    fn typed_extensions_yields_every_entry_of_the_type() {
+        // an outside writer's layout, add_extension keeps one TLV per type
+        let mut bytes = account_bytes(&[
+            (2, 0, &[1; 2]),
+            (0, 0, &[9; 4]),
+            (2, 1, &[2; 2]),
+            (2, 0, &[3; 3]),
+            (2, 0, &[4; 2]),
+        ]);
+
+        let mut cs = Base::typed_extensions::<C>(&bytes);
+        assert_eq!(
+            cs.next().map(|info| (info.ext.v, info.position)),
+            Some(([1; 2], 18))
+        );
+
+        let rest: Vec<_> = cs.map(|info| (info.ext.v, info.state)).collect();
+        // the 3 byte entry does not unpack as C and is skipped
+        assert_eq!(
+            rest,
+            vec![
+                ([2; 2], ExtensionState::Zerod),
+                ([4; 2], ExtensionState::Initialized)
+            ]
+        );
+
+        bytes.extend_from_slice(&[2, 0, 9, 0, 5]);
+        assert_eq!(Base::typed_extensions::<C>(&bytes).count(), 3);
+        assert_eq!(Base::typed_extensions::<C>(&[3; 10]).count(), 0);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn audit_consistency_flags_a_listed_type_get_extension_misses() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [2; 2] }).unwrap();
+        }
+        assert!(Base::audit_consistency::<Kind, A>(&acc.info));
+        assert!(Base::audit_consistency::<Kind, B>(&acc.info));
+        assert!(Base::audit_consistency::<Kind, C>(&acc.info));
+
+        // C's length patched to 1, still listed but no longer unpacks as a C
+        let mut corrupt = data(&acc);
+        corrupt[28] = 1;
+        let corrupt = make_acc([1; 32], OWNER, 1, &corrupt, 0);
+
+        assert!(!Base::audit_consistency::<Kind, C>(&corrupt.info));
+        assert!(Base::audit_consistency::<Kind, A>(&corrupt.info));
+    }
+
+    #[test]
+    fn get_live_extension_treats_zerod_as_absent() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            assert!(Base::get_live_extension::<A>(&acc.info, Kind::A).is_none());
+
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            assert_eq!(
+                Base::get_live_extension::<A>(&acc.info, Kind::A).map(|info| info.ext.v),
+                Some([1; 4])
+            );
+
+            Base::zero_out_extension_data::<A>(&acc.info, Kind::A).unwrap();
+            assert!(Base::get_live_extension::<A>(&acc.info, Kind::A).is_none());
+            assert_eq!(
+                Base::get_extension::<A>(&acc.info, Kind::A).map(|info| info.state),
+                Some(ExtensionState::Zerod)
+            );
+
+            Base::reinitialize_extension(&acc.info, Kind::A, &A { v: [2; 4] }).unwrap();
+            assert_eq!(
+                Base::get_live_extension::<A>(&acc.info, Kind::A).map(|info| info.ext.v),
+                Some([2; 4])
+            );
+        }
+    }
+
+    #[test]
+    fn zero_all_extensions_wipes_every_initialized_payload() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &B { v: [2; 8] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [3; 2] }).unwrap();
+            Base::zero_out_extension_data::<B>(&acc.info, Kind::B).unwrap();
+
+            // B was already Zerod
+            assert_eq!(Base::zero_all_extensions(&acc.info), Ok(2));
+        }
+        assert_eq!(
+            data(&acc)[10..],
+            account_bytes(&[(0, 1, &[0; 4]), (1, 1, &[0; 8]), (2, 1, &[0; 2])])[10..]
+        );
+        assert_eq!(unsafe { Base::zero_all_extensions(&acc.info) }, Ok(0));
+
+        let (protected, _, _) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&protected.info, &payer.info, &rent.info, &A { v: [1; 4] })
+                .unwrap();
+            Base::add_extension_with_authority(
+                &protected.info,
+                &payer.info,
+                &rent.info,
+                &C { v: [3; 2] },
+                &[9; 32],
+            )
+            .unwrap();
+        }
+        let before = data(&protected);
+
+        assert_eq!(
+            unsafe { Base::zero_all_extensions(&protected.info) },
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        assert_eq!(data(&protected), before);
+    }
+
+    #[test]
+    fn sort_extensions_by_orders_on_a_payload_field() {
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [9, 0, 0, 1] })
+                .unwrap();
+            Base::add_extension(
+                &acc.info,
+                &payer.info,
+                &rent.info,
+                &B {
+                    v: [3, 0, 0, 0, 0, 0, 0, 2],
+                },
+            )
+            .unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [5, 0] }).unwrap();
+        }
+        let len = data(&acc).len();
+
+        // the little endian u64 the payload starts with, shorter payloads zero
+        // extended
+        let field = |_: u8, payload: &[u8]| {
+            let mut field = [0; 8];
+            let n = payload.len().min(8);
+            field[..n].copy_from_slice(&payload[..n]);
+            u64::from_le_bytes(field)
+        };
+
+        unsafe { Base::sort_extensions_by(&acc.info, field).unwrap() };
+
+        assert_eq!(
+            Base::tlv_iter(&data(&acc))
+                .unwrap()
+                .map(|tlv| (tlv.ext_type, tlv.position))
+                .collect::<Vec<_>>(),
+            vec![(2, 18), (0, 24), (1, 32)]
+        );
+        assert_eq!(data(&acc).len(), len);
+
+        unsafe {
+            assert_eq!(
+                Base::get_extension::<B>(&acc.info, Kind::B).unwrap().ext.v,
+                [3, 0, 0, 0, 0, 0, 0, 2]
+            );
+        }
+    }
+
+    #[test]
+    fn conforms_to_schema_checks_types_and_lengths() {
+        let bytes = account_bytes(&[(0, 0, &[1; 4]), (2, 1, &[2; 2]), (1, 0, &[3; 8])]);
+
+        assert!(Base::conforms_to_schema(&bytes, &[(1, 8), (0, 4)]));
+        assert!(Base::conforms_to_schema(&bytes, &[(2, 2), (1, 8), (0, 4)]));
+        assert!(Base::conforms_to_schema(&bytes, &[]));
+
+        // a type missing, a length off
+        assert!(!Base::conforms_to_schema(&bytes, &[(0, 4), (3, 1)]));
+        assert!(!Base::conforms_to_schema(&bytes, &[(0, 4), (1, 7)]));
+        assert!(!Base::conforms_to_schema(&[3; 10], &[(0, 4)]));
+    }
+
+    #[test]
+    fn normalize_states_repairs_undefined_state_bytes() {
+        let bytes = account_bytes(&[(0, 0x05, &[1; 4]), (1, 1, &[2; 8]), (2, 0x09, &[3; 2])]);
+        let acc = make_acc([1; 32], OWNER, 1, &bytes, 0);
+
+        unsafe {
+            assert!(Base::get_extension::<C>(&acc.info, Kind::C).is_none());
+            assert_eq!(Base::detect_format(&bytes), FormatVersion::Unknown);
+
+            assert_eq!(Base::normalize_states(&acc.info), Ok(2));
+
+            assert_eq!(
+                data(&acc)[10..],
+                account_bytes(&[(0, 0, &[1; 4]), (1, 1, &[2; 8]), (2, 0, &[3; 2])])[10..]
+            );
+            assert_eq!(
+                Base::get_extension::<C>(&acc.info, Kind::C).map(|info| (info.ext.v, info.state)),
+                Some(([3; 2], ExtensionState::Initialized))
+            );
+            // a valid Zerod is left as it is
+            assert_eq!(
+                Base::get_extension::<B>(&acc.info, Kind::B).map(|info| info.state),
+                Some(ExtensionState::Zerod)
+            );
+            assert_eq!(Base::detect_format(&data(&acc)), FormatVersion::Original);
+            assert_eq!(Base::normalize_states(&acc.info), Ok(0));
+        }
+    }
+
+    #[test]
+    fn registry_reads_a_mixed_account_into_the_sum_type() {
+        #[repr(C)]
+        #[derive(Clone, Copy)]
+        struct Config {
+            v: [u8; 4],
+        }
+
+        #[repr(C)]
+        #[derive(Clone, Copy)]
+        struct Fees {
+            v: [u8; 2],
+        }
+
+        impl Extension for Config {
+            const LEN: u16 = 4;
+            type ExtensionEnum = Kind;
+            fn ext_type() -> u8 {
+                0
+            }
+        }
+
+        impl Extension for Fees {
+            const LEN: u16 = 2;
+            type ExtensionEnum = Kind;
+            fn ext_type() -> u8 {
+                2
+            }
+        }
+
+        crate::define_extension_registry! {
+            enum AnyExtension for Base {
+                Config(Config),
+                Fees(Fees),
+            }
+        }
+
+        // B is outside the registry, the 3 byte Fees does not unpack
+        let bytes = account_bytes(&[
+            (2, 0, &[5, 6]),
+            (1, 0, &[9; 8]),
+            (0, 1, &[1, 2, 3, 4]),
+            (2, 0, &[7; 3]),
+        ]);
+
+        let read: Vec<_> = AnyExtension::read_all(&bytes)
+            .into_iter()
+            .map(|ext| match ext {
+                AnyExtension::Config(config) => (0, config.v.to_vec()),
+                AnyExtension::Fees(fees) => (2, fees.v.to_vec()),
+            })
+            .collect();
+
+        assert_eq!(read, vec![(2, vec![5, 6]), (0, vec![1, 2, 3, 4])]);
+        assert!(AnyExtension::read_all(&[3; 10]).is_empty());
+    }
+
+    #[test]
+    fn prefunded_rent_covers_the_later_adds() {
+        let (_, payer, rent) = accounts(&[]);
+        let base_rent = minimum_balance(&rent, 10);
+        let prefunded = make_acc([1; 32], OWNER, base_rent, &[3; 10], 64);
+        let unfunded = make_acc([1; 32], OWNER, base_rent, &[3; 10], 64);
+
+        unsafe {
+            Base::prefund_extensions::<A>(&prefunded.info, &payer.info, &rent.info, 2).unwrap();
+        }
+        assert_eq!(data(&prefunded), [3; 10]);
+
+        // a Transfer CPI moves nothing off-chain, what it would have moved is
+        // credited by hand
+        *prefunded.info.try_borrow_mut_lamports().unwrap() +=
+            2 * minimum_balance(&rent, A::ext_with_meta_len());
+
+        let rent_sysvar = Rent::from_account_info(&rent.info).unwrap();
+
+        for acc in [&prefunded, &unfunded] {
+            unsafe {
+                Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+                Base::add_extension(&acc.info, &payer.info, &rent.info, &C { v: [2; 2] }).unwrap();
+            }
+        }
+
+        // with the transfers for the adds going nowhere as well, only the
+        // prefunded account still covers its size
+        assert_eq!(
+            prefunded.info.lamports(),
+            base_rent + 2 * minimum_balance(&rent, 8)
+        );
+        assert!(Base::is_rent_exempt_for_extensions(
+            &prefunded.info,
+            &rent_sysvar
+        ));
+        assert!(!Base::is_rent_exempt_for_extensions(
+            &unfunded.info,
+            &rent_sysvar
+        ));
+
+        let foreign = make_acc([1; 32], [8; 32], 1, &[3; 10], 0);
+        assert_eq!(
+            unsafe { Base::prefund_extensions::<A>(&foreign.info, &payer.info, &rent.info, 2) },
+            Err(ProgramError::IllegalOwner)
+        );
+    }
+
+    #[test]
+    fn swap_payloads_exchanges_values_in_place() {
+        #[repr(C)]
+        struct Limits {
+            v: [u8; 4],
+        }
+
+        impl Extension for Limits {
+            const LEN: u16 = 4;
+            type ExtensionEnum = Kind;
+            fn ext_type() -> u8 {
+                2
+            }
+        }
+
+        let (acc, payer, rent) = accounts(&[3; 10]);
+
+        unsafe {
+            assert_eq!(
+                Base::swap_payloads::<A>(&acc.info, Kind::A, Kind::C),
+                Err(StateExtensionError::ExtensionNotFound.into())
+            );
+
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &A { v: [1; 4] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &B { v: [2; 8] }).unwrap();
+            Base::add_extension(&acc.info, &payer.info, &rent.info, &Limits { v: [3; 4] }).unwrap();
+
+            Base::swap_payloads::<A>(&acc.info, Kind::A, Kind::C).unwrap();
+
+            let a = Base::get_extension::<A>(&acc.info, Kind::A).unwrap();
+            let limits = Base::get_extension::<Limits>(&acc.info, Kind::C).unwrap();
+            assert_eq!((a.ext.v, a.position), ([3; 4], 18));
+            assert_eq!((limits.ext.v, limits.position), ([1; 4], 38));
+
+            assert_eq!(
+                Base::swap_payloads::<A>(&acc.info, Kind::A, Kind::B),
+                Err(StateExtensionError::ExtensionLengthMismatch.into())
+            );
+            assert_eq!(
+                Base::get_extension::<B>(&acc.info, Kind::B).unwrap().ext.v,
+                [2; 8]
+            );
+        }
+    }
+
+    #[test]
+    fn read_field_copies_an_unaligned_field() {
+        let value = 0x0102_0304_0506_0708u64;
+        let mut payload = [0xaau8; 16];
+        payload[3..11].copy_from_slice(&value.to_le_bytes());
+
+        let bytes = account_bytes(&[(0, 0, &[9; 4]), (1, 0, &payload)]);
+
+        assert_eq!(
+            Base::read_field::<Kind, u64>(&bytes, Kind::B, 3),
+            Some(value)
+        );
+        assert_eq!(
+            Base::read_field::<Kind, u32>(&bytes, Kind::A, 0),
+            Some(u32::from_le_bytes([9; 4]))
+        );
+        assert_eq!(Base::read_field::<Kind, u64>(&bytes, Kind::B, 9), None);
+        assert_eq!(Base::read_field::<Kind, u64>(&bytes, Kind::C, 0), None);
     }
 }